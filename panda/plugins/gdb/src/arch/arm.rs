@@ -0,0 +1,341 @@
+//! 32-bit ARM with the VFPv3/NEON registers and the registers banked for the other CPU
+//! modes, on top of the core registers from `gdbstub_arch`.
+
+use gdbstub::arch::{Arch, RegId, Registers};
+use gdbstub_arch::arm::{ArmBreakpointKind, reg::ArmCoreRegs, reg::id::ArmCoreRegId};
+use panda::sys::CPUARMState;
+
+use std::convert::TryInto;
+use std::num::NonZeroUsize;
+
+const CORE_XML: &str = include_str!("xml/arm-core.xml");
+const VFP_XML: &str = include_str!("xml/arm-vfpv3.xml");
+
+/// gdb adds the `q` registers on top of the `d` registers when this feature exists
+const NEON_XML: &str = r#"<feature name="org.gnu.gdb.arm.neon"/>"#;
+
+/// Same feature as QEMU's `arm-banked.xml`
+const BANKED_FEATURE: &str = "org.gnu.gdb.arm.banked";
+
+/// r0-r12, sp, lr, pc and cpsr
+const CORE_REGS: usize = 17;
+const D_REGS: usize = 32;
+
+const CPSR_M: u32 = 0x1f;
+const MODE_USR: u32 = 0x10;
+const MODE_FIQ: u32 = 0x11;
+const MODE_IRQ: u32 = 0x12;
+const MODE_SVC: u32 = 0x13;
+const MODE_MON: u32 = 0x16;
+const MODE_ABT: u32 = 0x17;
+const MODE_HYP: u32 = 0x1a;
+const MODE_UND: u32 = 0x1b;
+const MODE_SYS: u32 = 0x1f;
+
+/// A register of a mode other than the current one. gdb can't write these, as writes
+/// to them would be lost or clobber the current mode's registers on a mode switch.
+struct BankedReg {
+    name: &'static str,
+    read: fn(&CPUARMState) -> u32,
+}
+
+// In the order of `arm-banked.xml`
+const BANKED_REGS: &[BankedReg] = &[
+    BankedReg { name: "sp_usr", read: |env| banked_sp(env, MODE_USR) },
+    BankedReg { name: "lr_usr", read: |env| banked_lr(env, MODE_USR) },
+    BankedReg { name: "r8_fiq", read: |env| fiq_reg(env, 0) },
+    BankedReg { name: "r9_fiq", read: |env| fiq_reg(env, 1) },
+    BankedReg { name: "r10_fiq", read: |env| fiq_reg(env, 2) },
+    BankedReg { name: "r11_fiq", read: |env| fiq_reg(env, 3) },
+    BankedReg { name: "r12_fiq", read: |env| fiq_reg(env, 4) },
+    BankedReg { name: "sp_fiq", read: |env| banked_sp(env, MODE_FIQ) },
+    BankedReg { name: "lr_fiq", read: |env| banked_lr(env, MODE_FIQ) },
+    BankedReg { name: "sp_irq", read: |env| banked_sp(env, MODE_IRQ) },
+    BankedReg { name: "lr_irq", read: |env| banked_lr(env, MODE_IRQ) },
+    BankedReg { name: "sp_svc", read: |env| banked_sp(env, MODE_SVC) },
+    BankedReg { name: "lr_svc", read: |env| banked_lr(env, MODE_SVC) },
+    BankedReg { name: "sp_abt", read: |env| banked_sp(env, MODE_ABT) },
+    BankedReg { name: "lr_abt", read: |env| banked_lr(env, MODE_ABT) },
+    BankedReg { name: "sp_und", read: |env| banked_sp(env, MODE_UND) },
+    BankedReg { name: "lr_und", read: |env| banked_lr(env, MODE_UND) },
+    BankedReg { name: "spsr_fiq", read: |env| banked_spsr(env, MODE_FIQ) },
+    BankedReg { name: "spsr_irq", read: |env| banked_spsr(env, MODE_IRQ) },
+    BankedReg { name: "spsr_svc", read: |env| banked_spsr(env, MODE_SVC) },
+    BankedReg { name: "spsr_abt", read: |env| banked_spsr(env, MODE_ABT) },
+    BankedReg { name: "spsr_und", read: |env| banked_spsr(env, MODE_UND) },
+];
+
+const BANKED_REG_COUNT: usize = BANKED_REGS.len();
+
+/// Equivalent of QEMU's `bank_number`
+fn bank_number(mode: u32) -> Option<usize> {
+    Some(match mode {
+        MODE_USR | MODE_SYS => 0,
+        MODE_SVC => 1,
+        MODE_ABT => 2,
+        MODE_UND => 3,
+        MODE_IRQ => 4,
+        MODE_FIQ => 5,
+        MODE_HYP => 6,
+        MODE_MON => 7,
+        _ => return None,
+    })
+}
+
+fn current_mode(env: &CPUARMState) -> u32 {
+    env.uncached_cpsr & CPSR_M
+}
+
+/// The registers of the current mode are live in `regs`, the banked copies are only
+/// updated when switching modes
+fn banked_sp(env: &CPUARMState, mode: u32) -> u32 {
+    let bank = bank_number(mode).unwrap();
+    if bank_number(current_mode(env)) == Some(bank) {
+        env.regs[13]
+    } else {
+        env.banked_r13[bank]
+    }
+}
+
+fn banked_lr(env: &CPUARMState, mode: u32) -> u32 {
+    // Hyp mode shares lr with usr/sys, see QEMU's `r14_bank_number`
+    let r14_bank = |mode| if mode == MODE_HYP { Some(0) } else { bank_number(mode) };
+
+    let bank = r14_bank(mode).unwrap();
+    if r14_bank(current_mode(env)) == Some(bank) {
+        env.regs[14]
+    } else {
+        env.banked_r14[bank]
+    }
+}
+
+fn banked_spsr(env: &CPUARMState, mode: u32) -> u32 {
+    let bank = bank_number(mode).unwrap();
+    if bank_number(current_mode(env)) == Some(bank) {
+        env.spsr
+    } else {
+        env.banked_spsr[bank] as u32
+    }
+}
+
+/// r8-r12 are also banked in fiq mode
+fn fiq_reg(env: &CPUARMState, i: usize) -> u32 {
+    if current_mode(env) == MODE_FIQ {
+        env.regs[8 + i]
+    } else {
+        env.fiq_regs[i]
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref TARGET_XML: String = target_xml();
+}
+
+fn target_xml() -> String {
+    let mut xml = String::from(
+        r#"<?xml version="1.0"?><!DOCTYPE target SYSTEM "gdb-target.dtd"><target version="1.0"><architecture>arm</architecture>"#,
+    );
+
+    xml.push_str(CORE_XML);
+    xml.push_str(VFP_XML);
+    xml.push_str(NEON_XML);
+
+    xml.push_str(&format!(r#"<feature name="{}">"#, BANKED_FEATURE));
+    for reg in BANKED_REGS {
+        xml.push_str(&format!(r#"<reg name="{}" bitsize="32" group="banked"/>"#, reg.name));
+    }
+    xml.push_str("</feature>");
+
+    xml.push_str("</target>");
+    xml
+}
+
+/// ARM with VFPv3/NEON and banked registers
+pub enum Arm {}
+
+impl Arch for Arm {
+    type Usize = u32;
+    type Registers = ArmRegs;
+    type RegId = ArmRegId;
+    type BreakpointKind = ArmBreakpointKind;
+
+    fn target_description_xml() -> Option<&'static str> {
+        Some(&TARGET_XML)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ArmRegs {
+    /// The core registers. The FPA registers `gdbstub_arch` puts between the PC and CPSR
+    /// aren't part of the target description.
+    pub core: ArmCoreRegs,
+    pub d: [u64; D_REGS],
+    pub fpscr: u32,
+    /// Values of the registers in `BANKED_REGS`
+    pub banked: [u32; BANKED_REG_COUNT],
+}
+
+impl Registers for ArmRegs {
+    type ProgramCounter = u32;
+
+    fn pc(&self) -> Self::ProgramCounter {
+        self.core.pc
+    }
+
+    fn gdb_serialize(&self, mut write_byte: impl FnMut(Option<u8>)) {
+        let mut write_bytes = |bytes: &[u8]| bytes.iter().for_each(|&byte| write_byte(Some(byte)));
+
+        for reg in self.core.r.iter().chain(&[self.core.sp, self.core.lr, self.core.pc, self.core.cpsr]) {
+            write_bytes(&reg.to_le_bytes());
+        }
+
+        for reg in &self.d {
+            write_bytes(&reg.to_le_bytes());
+        }
+
+        write_bytes(&self.fpscr.to_le_bytes());
+
+        for reg in &self.banked {
+            write_bytes(&reg.to_le_bytes());
+        }
+    }
+
+    fn gdb_deserialize(&mut self, bytes: &[u8]) -> Result<(), ()> {
+        if bytes.len() < (CORE_REGS + 1 + BANKED_REG_COUNT) * 4 + D_REGS * 8 {
+            return Err(());
+        }
+
+        let mut words = bytes.chunks_exact(4).map(|word| u32::from_le_bytes(word.try_into().unwrap()));
+
+        for reg in self.core.r.iter_mut() {
+            *reg = words.next().unwrap();
+        }
+
+        self.core.sp = words.next().unwrap();
+        self.core.lr = words.next().unwrap();
+        self.core.pc = words.next().unwrap();
+        self.core.cpsr = words.next().unwrap();
+
+        let bytes = &bytes[CORE_REGS * 4..];
+        for (reg, bytes) in self.d.iter_mut().zip(bytes.chunks_exact(8)) {
+            *reg = u64::from_le_bytes(bytes.try_into().unwrap());
+        }
+
+        let mut words = bytes[D_REGS * 8..]
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()));
+
+        self.fpscr = words.next().unwrap();
+        for reg in self.banked.iter_mut() {
+            *reg = words.next().unwrap();
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ArmRegId {
+    Core(ArmCoreRegId),
+    D(usize),
+    Fpscr,
+    /// Index into `BANKED_REGS`
+    Banked(usize),
+}
+
+impl RegId for ArmRegId {
+    fn from_raw_id(id: usize) -> Option<(Self, Option<NonZeroUsize>)> {
+        let (id, size) = match id {
+            0..=12 => (ArmRegId::Core(ArmCoreRegId::Gpr(id as u8)), 4),
+            13 => (ArmRegId::Core(ArmCoreRegId::Sp), 4),
+            14 => (ArmRegId::Core(ArmCoreRegId::Lr), 4),
+            15 => (ArmRegId::Core(ArmCoreRegId::Pc), 4),
+            16 => (ArmRegId::Core(ArmCoreRegId::Cpsr), 4),
+            _ if id < CORE_REGS + D_REGS => (ArmRegId::D(id - CORE_REGS), 8),
+            _ if id == CORE_REGS + D_REGS => (ArmRegId::Fpscr, 4),
+            _ if id < CORE_REGS + D_REGS + 1 + BANKED_REG_COUNT => {
+                (ArmRegId::Banked(id - CORE_REGS - D_REGS - 1), 4)
+            }
+            _ => return None,
+        };
+
+        Some((id, NonZeroUsize::new(size)))
+    }
+}
+
+pub fn read_banked_regs(env: &CPUARMState) -> [u32; BANKED_REG_COUNT] {
+    let mut banked = [0; BANKED_REG_COUNT];
+    for (reg, val) in BANKED_REGS.iter().zip(banked.iter_mut()) {
+        *val = (reg.read)(env);
+    }
+
+    banked
+}
+
+/// The address a breakpoint of the given kind applies to. gdb can leave the Thumb bit
+/// set in the address, e.g. for `break *func` with `func` being a Thumb function pointer.
+pub fn breakpoint_addr(addr: u32, kind: &ArmBreakpointKind) -> u32 {
+    match kind {
+        ArmBreakpointKind::Thumb16 | ArmBreakpointKind::Thumb32 => addr & !1,
+        ArmBreakpointKind::Arm32 => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::{described_regs, described_size};
+
+    fn sample_regs() -> ArmRegs {
+        let mut regs = ArmRegs::default();
+
+        for (i, reg) in regs.core.r.iter_mut().enumerate() {
+            *reg = 0x1000 + i as u32;
+        }
+        regs.core.sp = 0xbeff_f000;
+        regs.core.lr = 0x0001_0435;
+        regs.core.pc = 0x0001_0400;
+        regs.core.cpsr = 0x6000_0010;
+
+        for (i, reg) in regs.d.iter_mut().enumerate() {
+            *reg = 0x0123_4567_0000_0000 | i as u64;
+        }
+        regs.fpscr = 0x0300_0000;
+
+        for (i, reg) in regs.banked.iter_mut().enumerate() {
+            *reg = 0x2000 + i as u32;
+        }
+
+        regs
+    }
+
+    #[test]
+    fn serialized_size_matches_target_description() {
+        let mut len = 0;
+        sample_regs().gdb_serialize(|_| len += 1);
+
+        assert_eq!(len, described_size(&TARGET_XML));
+    }
+
+    #[test]
+    fn reg_ids_match_target_description() {
+        let regs = described_regs(&TARGET_XML);
+
+        assert!(ArmRegId::from_raw_id(regs - 1).is_some());
+        assert!(ArmRegId::from_raw_id(regs).is_none());
+    }
+
+    #[test]
+    fn serialize_round_trip() {
+        let regs = sample_regs();
+
+        let mut bytes = Vec::new();
+        regs.gdb_serialize(|byte| bytes.push(byte.unwrap()));
+
+        let mut deserialized = ArmRegs::default();
+        deserialized.gdb_deserialize(&bytes).unwrap();
+
+        assert_eq!(deserialized, regs);
+        assert!(ArmRegs::default().gdb_deserialize(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
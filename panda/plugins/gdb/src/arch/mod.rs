@@ -0,0 +1,26 @@
+//! Architectures which describe more registers than the ones in `gdbstub_arch`, for
+//! debugging the kernel as well as user space
+
+#[cfg(any(feature = "x86_64", feature = "i386"))]
+pub mod x86;
+
+#[cfg(feature = "arm")]
+pub mod arm;
+
+/// Total size in bytes of the registers in a target description
+#[cfg(test)]
+fn described_size(xml: &str) -> usize {
+    let bits: usize = xml
+        .split(r#"bitsize=""#)
+        .skip(1)
+        .map(|rest| rest[..rest.find('"').unwrap()].parse::<usize>().unwrap())
+        .sum();
+
+    bits / 8
+}
+
+/// Number of registers in a target description
+#[cfg(test)]
+fn described_regs(xml: &str) -> usize {
+    xml.matches("<reg ").count()
+}
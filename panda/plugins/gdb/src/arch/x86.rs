@@ -0,0 +1,638 @@
+//! x86 and x86_64 with the registers needed for kernel and TLS debugging on top of the
+//! `gdbstub_arch` core and SSE registers: the fs/gs segment bases, control registers,
+//! descriptor table registers and system call MSRs, as well as the AVX and AVX-512
+//! registers.
+
+use gdbstub::arch::{Arch, RegId, Registers};
+use panda::prelude::target_ulong;
+use panda::sys::CPUX86State;
+
+use std::convert::TryInto;
+use std::num::NonZeroUsize;
+
+#[cfg(feature = "x86_64")]
+use gdbstub_arch::x86::reg::{X86_64CoreRegs as CoreRegs, id::X86_64CoreRegId as CoreRegId};
+
+#[cfg(feature = "i386")]
+use gdbstub_arch::x86::reg::{X86CoreRegs as CoreRegs, id::X86CoreRegId as CoreRegId};
+
+#[cfg(feature = "x86_64")]
+mod layout {
+    pub const ARCHITECTURE: &str = "i386:x86-64";
+    pub const CORE_XML: &str = include_str!("xml/64bit-core.xml");
+    pub const SSE_XML: &str = include_str!("xml/64bit-sse.xml");
+
+    /// Number of registers in the core and SSE features
+    pub const CORE_REGS: usize = 57;
+    /// Size of the core and SSE registers in a `g` packet
+    pub const CORE_LEN: usize = 0x218;
+    /// `gdbstub_arch` pads its registers out to gdb's default amd64 Linux layout, which
+    /// isn't used here
+    pub const CORE_PADDING: usize = 0x18;
+
+    /// Number of vector registers covered by the SSE feature
+    pub const XMM_REGS: usize = 16;
+    /// Number of vector registers with AVX-512
+    pub const ZMM_REGS: usize = 32;
+}
+
+#[cfg(feature = "i386")]
+mod layout {
+    pub const ARCHITECTURE: &str = "i386";
+    pub const CORE_XML: &str = include_str!("xml/32bit-core.xml");
+    pub const SSE_XML: &str = include_str!("xml/32bit-sse.xml");
+
+    pub const CORE_REGS: usize = 41;
+    pub const CORE_LEN: usize = 0x134;
+    pub const CORE_PADDING: usize = 4;
+
+    pub const XMM_REGS: usize = 8;
+    pub const ZMM_REGS: usize = 8;
+}
+
+use layout::*;
+
+const SYSTEM_FEATURE: &str = "org.panda-re.gdb.i386.system";
+const AVX_FEATURE: &str = "org.gnu.gdb.i386.avx";
+const AVX512_FEATURE: &str = "org.gnu.gdb.i386.avx512";
+
+/// Types used by the AVX-512 feature, types from the SSE feature can't be used in it
+const AVX512_TYPES: &str = concat!(
+    r#"<vector id="v4f" type="ieee_single" count="4"/>"#,
+    r#"<vector id="v2d" type="ieee_double" count="2"/>"#,
+    r#"<vector id="v16i8" type="int8" count="16"/>"#,
+    r#"<vector id="v8i16" type="int16" count="8"/>"#,
+    r#"<vector id="v4i32" type="int32" count="4"/>"#,
+    r#"<vector id="v2i64" type="int64" count="2"/>"#,
+    r#"<union id="vec128">"#,
+    r#"<field name="v4_float" type="v4f"/>"#,
+    r#"<field name="v2_double" type="v2d"/>"#,
+    r#"<field name="v16_int8" type="v16i8"/>"#,
+    r#"<field name="v8_int16" type="v8i16"/>"#,
+    r#"<field name="v4_int32" type="v4i32"/>"#,
+    r#"<field name="v2_int64" type="v2i64"/>"#,
+    r#"<field name="uint128" type="uint128"/>"#,
+    r#"</union>"#,
+    r#"<vector id="v2ui128" type="uint128" count="2"/>"#,
+);
+
+/// Size of pointer sized registers
+const PTR_BITS: usize = std::mem::size_of::<target_ulong>() * 8;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Feature {
+    /// gdb's own feature for `fs_base` and `gs_base`, which it uses for TLS
+    Segments,
+    System,
+}
+
+/// A register which isn't part of the `gdbstub_arch` core registers
+struct SystemReg {
+    name: &'static str,
+    bits: usize,
+    feature: Feature,
+    read: fn(&CPUX86State) -> u64,
+    /// None if gdb can't change the register
+    write: Option<fn(&mut CPUX86State, u64)>,
+}
+
+// Registers of the same feature have to be next to each other, as the order here is
+// the order in the target description.
+const SYSTEM_REGS: &[SystemReg] = &[
+    SystemReg {
+        name: "fs_base",
+        bits: PTR_BITS,
+        feature: Feature::Segments,
+        read: |env| env.segs[panda::sys::R_FS as usize].base as u64,
+        write: Some(|env, val| env.segs[panda::sys::R_FS as usize].base = val as _),
+    },
+    SystemReg {
+        name: "gs_base",
+        bits: PTR_BITS,
+        feature: Feature::Segments,
+        read: |env| env.segs[panda::sys::R_GS as usize].base as u64,
+        write: Some(|env, val| env.segs[panda::sys::R_GS as usize].base = val as _),
+    },
+    SystemReg {
+        name: "cr0",
+        bits: PTR_BITS,
+        feature: Feature::System,
+        read: |env| env.cr[0] as u64,
+        write: Some(|env, val| unsafe { panda::sys::cpu_x86_update_cr0(env, val as u32) }),
+    },
+    SystemReg {
+        name: "cr2",
+        bits: PTR_BITS,
+        feature: Feature::System,
+        read: |env| env.cr[2] as u64,
+        write: Some(|env, val| env.cr[2] = val as _),
+    },
+    SystemReg {
+        name: "cr3",
+        bits: PTR_BITS,
+        feature: Feature::System,
+        read: |env| env.cr[3] as u64,
+        write: Some(|env, val| unsafe { panda::sys::cpu_x86_update_cr3(env, val as _) }),
+    },
+    SystemReg {
+        name: "cr4",
+        bits: PTR_BITS,
+        feature: Feature::System,
+        read: |env| env.cr[4] as u64,
+        write: Some(|env, val| unsafe { panda::sys::cpu_x86_update_cr4(env, val as u32) }),
+    },
+    SystemReg {
+        name: "efer",
+        bits: 64,
+        feature: Feature::System,
+        read: |env| env.efer,
+        write: Some(load_efer),
+    },
+    SystemReg {
+        name: "gdtr_base",
+        bits: PTR_BITS,
+        feature: Feature::System,
+        read: |env| env.gdt.base as u64,
+        write: Some(|env, val| env.gdt.base = val as _),
+    },
+    SystemReg {
+        name: "gdtr_limit",
+        bits: 32,
+        feature: Feature::System,
+        read: |env| env.gdt.limit as u64,
+        write: Some(|env, val| env.gdt.limit = val as u32),
+    },
+    SystemReg {
+        name: "idtr_base",
+        bits: PTR_BITS,
+        feature: Feature::System,
+        read: |env| env.idt.base as u64,
+        write: Some(|env, val| env.idt.base = val as _),
+    },
+    SystemReg {
+        name: "idtr_limit",
+        bits: 32,
+        feature: Feature::System,
+        read: |env| env.idt.limit as u64,
+        write: Some(|env, val| env.idt.limit = val as u32),
+    },
+    // Loading these would need their descriptors, which gdb has no way of changing
+    SystemReg {
+        name: "ldtr",
+        bits: 32,
+        feature: Feature::System,
+        read: |env| env.ldt.selector as u64,
+        write: None,
+    },
+    SystemReg {
+        name: "tr",
+        bits: 32,
+        feature: Feature::System,
+        read: |env| env.tr.selector as u64,
+        write: None,
+    },
+    #[cfg(feature = "x86_64")]
+    SystemReg {
+        name: "kernel_gs_base",
+        bits: 64,
+        feature: Feature::System,
+        read: |env| env.kernelgsbase as u64,
+        write: Some(|env, val| env.kernelgsbase = val as _),
+    },
+    #[cfg(feature = "x86_64")]
+    SystemReg {
+        name: "star",
+        bits: 64,
+        feature: Feature::System,
+        read: |env| env.star,
+        write: Some(|env, val| env.star = val),
+    },
+    #[cfg(feature = "x86_64")]
+    SystemReg {
+        name: "lstar",
+        bits: 64,
+        feature: Feature::System,
+        read: |env| env.lstar as u64,
+        write: Some(|env, val| env.lstar = val as _),
+    },
+    #[cfg(feature = "x86_64")]
+    SystemReg {
+        name: "cstar",
+        bits: 64,
+        feature: Feature::System,
+        read: |env| env.cstar as u64,
+        write: Some(|env, val| env.cstar = val as _),
+    },
+    #[cfg(feature = "x86_64")]
+    SystemReg {
+        name: "fmask",
+        bits: 64,
+        feature: Feature::System,
+        read: |env| env.fmask as u64,
+        write: Some(|env, val| env.fmask = val as _),
+    },
+    SystemReg {
+        name: "sysenter_cs",
+        bits: 32,
+        feature: Feature::System,
+        read: |env| env.sysenter_cs as u64,
+        write: Some(|env, val| env.sysenter_cs = val as u32),
+    },
+    SystemReg {
+        name: "sysenter_esp",
+        bits: PTR_BITS,
+        feature: Feature::System,
+        read: |env| env.sysenter_esp as u64,
+        write: Some(|env, val| env.sysenter_esp = val as _),
+    },
+    SystemReg {
+        name: "sysenter_eip",
+        bits: PTR_BITS,
+        feature: Feature::System,
+        read: |env| env.sysenter_eip as u64,
+        write: Some(|env, val| env.sysenter_eip = val as _),
+    },
+    SystemReg {
+        name: "pat",
+        bits: 64,
+        feature: Feature::System,
+        read: |env| env.pat,
+        write: Some(|env, val| env.pat = val),
+    },
+];
+
+const SYSTEM_REG_COUNT: usize = SYSTEM_REGS.len();
+
+/// Equivalent of QEMU's `cpu_load_efer`, which also updates the hidden flags
+fn load_efer(env: &mut CPUX86State, efer: u64) {
+    const EFER_LMA: u64 = 1 << 10;
+    const EFER_SVME: u64 = 1 << 12;
+    const HF_LMA_MASK: u32 = 1 << 14;
+    const HF_SVME_MASK: u32 = 1 << 21;
+
+    env.efer = efer;
+    env.hflags &= !(HF_LMA_MASK | HF_SVME_MASK);
+    if efer & EFER_LMA != 0 {
+        env.hflags |= HF_LMA_MASK;
+    }
+    if efer & EFER_SVME != 0 {
+        env.hflags |= HF_SVME_MASK;
+    }
+}
+
+/// A register of the AVX or AVX-512 features. gdb puts the `ymm` and `zmm` registers
+/// together from these and the `xmm` registers.
+struct VectorReg {
+    name: String,
+    ty: &'static str,
+    feature: &'static str,
+    value: VectorValue,
+}
+
+#[derive(Copy, Clone)]
+pub enum VectorValue {
+    /// The 128-bit lanes `start..end` of a ZMM register
+    Lanes { zmm: usize, start: usize, end: usize },
+    Opmask(usize),
+}
+
+impl VectorValue {
+    fn bits(&self) -> usize {
+        match self {
+            VectorValue::Lanes { start, end, .. } => (end - start) * 128,
+            VectorValue::Opmask(_) => 64,
+        }
+    }
+}
+
+// In the order of gdb's own AVX and AVX-512 target descriptions
+fn vector_regs() -> Vec<VectorReg> {
+    let lanes = |feature, name, ty, zmm, start, end| VectorReg {
+        name,
+        ty,
+        feature,
+        value: VectorValue::Lanes { zmm, start, end },
+    };
+
+    let mut regs: Vec<_> = (0..XMM_REGS)
+        .map(|i| lanes(AVX_FEATURE, format!("ymm{}h", i), "uint128", i, 1, 2))
+        .collect();
+
+    regs.extend((XMM_REGS..ZMM_REGS).map(|i| lanes(AVX512_FEATURE, format!("xmm{}", i), "vec128", i, 0, 1)));
+    regs.extend((XMM_REGS..ZMM_REGS).map(|i| lanes(AVX512_FEATURE, format!("ymm{}h", i), "uint128", i, 1, 2)));
+    regs.extend((0..8).map(|i| VectorReg {
+        name: format!("k{}", i),
+        ty: "uint64",
+        feature: AVX512_FEATURE,
+        value: VectorValue::Opmask(i),
+    }));
+    regs.extend((0..ZMM_REGS).map(|i| lanes(AVX512_FEATURE, format!("zmm{}h", i), "v2ui128", i, 2, 4)));
+
+    regs
+}
+
+lazy_static::lazy_static! {
+    static ref VECTOR_REGS: Vec<VectorReg> = vector_regs();
+    static ref TARGET_XML: String = target_xml();
+}
+
+fn target_xml() -> String {
+    let mut xml = format!(
+        r#"<?xml version="1.0"?><!DOCTYPE target SYSTEM "gdb-target.dtd"><target version="1.0"><architecture>{}</architecture>"#,
+        ARCHITECTURE
+    );
+
+    xml.push_str(CORE_XML);
+    xml.push_str(SSE_XML);
+
+    for &(feature, name) in &[(Feature::Segments, "org.gnu.gdb.i386.segments"), (Feature::System, SYSTEM_FEATURE)] {
+        xml.push_str(&format!(r#"<feature name="{}">"#, name));
+
+        for reg in SYSTEM_REGS.iter().filter(|reg| reg.feature == feature) {
+            let ty = if reg.name.ends_with("base") { "data_ptr" } else { "int" };
+            xml.push_str(&reg_xml(reg.name, reg.bits, ty));
+        }
+
+        xml.push_str("</feature>");
+    }
+
+    for &(feature, types) in &[(AVX_FEATURE, ""), (AVX512_FEATURE, AVX512_TYPES)] {
+        xml.push_str(&format!(r#"<feature name="{}">"#, feature));
+        xml.push_str(types);
+
+        for reg in VECTOR_REGS.iter().filter(|reg| reg.feature == feature) {
+            xml.push_str(&reg_xml(&reg.name, reg.value.bits(), reg.ty));
+        }
+
+        xml.push_str("</feature>");
+    }
+
+    xml.push_str("</target>");
+    xml
+}
+
+fn reg_xml(name: &str, bits: usize, ty: &str) -> String {
+    format!(r#"<reg name="{}" bitsize="{}" type="{}"/>"#, name, bits, ty)
+}
+
+/// x86 (or x86_64, depending on the feature) with system and AVX registers
+pub enum X86System {}
+
+impl Arch for X86System {
+    type Usize = target_ulong;
+    type Registers = X86Regs;
+    type RegId = X86RegId;
+    type BreakpointKind = usize;
+
+    fn target_description_xml() -> Option<&'static str> {
+        Some(&TARGET_XML)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct X86Regs {
+    pub core: CoreRegs,
+    /// Values of the registers in `SYSTEM_REGS`
+    pub system: [u64; SYSTEM_REG_COUNT],
+    /// The 128-bit lanes of the ZMM registers. The lanes which are XMM registers in the
+    /// SSE feature are in `core` instead.
+    pub zmm: [[u128; 4]; ZMM_REGS],
+    /// The AVX-512 opmask registers
+    pub k: [u64; 8],
+}
+
+impl Registers for X86Regs {
+    type ProgramCounter = target_ulong;
+
+    fn pc(&self) -> Self::ProgramCounter {
+        self.core.pc()
+    }
+
+    fn gdb_serialize(&self, mut write_byte: impl FnMut(Option<u8>)) {
+        let mut core = Vec::with_capacity(CORE_LEN + CORE_PADDING);
+        self.core.gdb_serialize(|byte| core.push(byte));
+
+        core.into_iter().take(CORE_LEN).for_each(&mut write_byte);
+
+        for (reg, val) in SYSTEM_REGS.iter().zip(self.system.iter()) {
+            for &byte in &val.to_le_bytes()[..reg.bits / 8] {
+                write_byte(Some(byte));
+            }
+        }
+
+        for reg in VECTOR_REGS.iter() {
+            match reg.value {
+                VectorValue::Lanes { zmm, start, end } => {
+                    for lane in &self.zmm[zmm][start..end] {
+                        lane.to_le_bytes().iter().for_each(|&byte| write_byte(Some(byte)));
+                    }
+                }
+                VectorValue::Opmask(i) => {
+                    self.k[i].to_le_bytes().iter().for_each(|&byte| write_byte(Some(byte)));
+                }
+            }
+        }
+    }
+
+    fn gdb_deserialize(&mut self, bytes: &[u8]) -> Result<(), ()> {
+        if bytes.len() < CORE_LEN {
+            return Err(());
+        }
+
+        let mut core = bytes[..CORE_LEN].to_vec();
+        core.resize(CORE_LEN + CORE_PADDING, 0);
+        self.core.gdb_deserialize(&core)?;
+
+        let mut bytes = &bytes[CORE_LEN..];
+        for (reg, val) in SYSTEM_REGS.iter().zip(self.system.iter_mut()) {
+            let len = reg.bits / 8;
+            if bytes.len() < len {
+                return Err(());
+            }
+
+            let mut le_bytes = [0; 8];
+            le_bytes[..len].copy_from_slice(&bytes[..len]);
+            *val = u64::from_le_bytes(le_bytes);
+
+            bytes = &bytes[len..];
+        }
+
+        for reg in VECTOR_REGS.iter() {
+            let len = reg.value.bits() / 8;
+            if bytes.len() < len {
+                return Err(());
+            }
+
+            match reg.value {
+                VectorValue::Lanes { zmm, start, end } => {
+                    for (lane, bytes) in self.zmm[zmm][start..end].iter_mut().zip(bytes.chunks(16)) {
+                        *lane = u128::from_le_bytes(bytes.try_into().unwrap());
+                    }
+                }
+                VectorValue::Opmask(i) => {
+                    self.k[i] = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+                }
+            }
+
+            bytes = &bytes[len..];
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum X86RegId {
+    Core(CoreRegId),
+    /// Index into `SYSTEM_REGS`
+    System(usize),
+    /// Index into `VECTOR_REGS`
+    Vector(usize),
+}
+
+impl RegId for X86RegId {
+    fn from_raw_id(id: usize) -> Option<(Self, Option<NonZeroUsize>)> {
+        if id < CORE_REGS {
+            let (id, size) = CoreRegId::from_raw_id(id)?;
+            Some((X86RegId::Core(id), size))
+        } else if id < CORE_REGS + SYSTEM_REG_COUNT {
+            let index = id - CORE_REGS;
+            Some((X86RegId::System(index), NonZeroUsize::new(SYSTEM_REGS[index].bits / 8)))
+        } else {
+            let index = id - CORE_REGS - SYSTEM_REG_COUNT;
+            let reg = VECTOR_REGS.get(index)?;
+            Some((X86RegId::Vector(index), NonZeroUsize::new(reg.value.bits() / 8)))
+        }
+    }
+}
+
+/// Size in bytes of the system register at `index`
+pub fn system_reg_size(index: usize) -> usize {
+    SYSTEM_REGS[index].bits / 8
+}
+
+/// Where the AVX or AVX-512 register at `index` is stored in `X86Regs`
+pub fn vector_reg(index: usize) -> Option<VectorValue> {
+    VECTOR_REGS.get(index).map(|reg| reg.value)
+}
+
+pub fn read_system_regs(env: &CPUX86State) -> [u64; SYSTEM_REG_COUNT] {
+    let mut system = [0; SYSTEM_REG_COUNT];
+    for (reg, val) in SYSTEM_REGS.iter().zip(system.iter_mut()) {
+        *val = (reg.read)(env);
+    }
+
+    system
+}
+
+/// Write the system registers which changed. Writes to read-only registers are ignored.
+pub fn write_system_regs(env: &mut CPUX86State, system: &[u64; SYSTEM_REG_COUNT], old: &[u64; SYSTEM_REG_COUNT]) {
+    for ((reg, &val), &old) in SYSTEM_REGS.iter().zip(system.iter()).zip(old.iter()) {
+        if let (Some(write), true) = (reg.write, val != old) {
+            write(env, val);
+        }
+    }
+}
+
+pub fn read_zmm(env: &CPUX86State) -> [[u128; 4]; ZMM_REGS] {
+    let mut zmm = [[0; 4]; ZMM_REGS];
+    for (lanes, reg) in zmm.iter_mut().zip(env.xmm_regs.iter()) {
+        for (i, lane) in lanes.iter_mut().enumerate() {
+            unsafe {
+                *lane = (reg._q_ZMMReg[i * 2 + 1] as u128) << 64 | reg._q_ZMMReg[i * 2] as u128;
+            }
+        }
+    }
+
+    zmm
+}
+
+/// Write the ZMM registers, except for the lanes which are loaded as XMM registers
+pub fn write_zmm(env: &mut CPUX86State, zmm: &[[u128; 4]; ZMM_REGS]) {
+    for (i, (lanes, reg)) in zmm.iter().zip(env.xmm_regs.iter_mut()).enumerate() {
+        let first = if i < XMM_REGS { 1 } else { 0 };
+
+        for (j, &lane) in lanes.iter().enumerate().skip(first) {
+            unsafe {
+                reg._q_ZMMReg[j * 2] = lane as u64;
+                reg._q_ZMMReg[j * 2 + 1] = (lane >> 64) as u64;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::{described_regs, described_size};
+
+    #[test]
+    fn core_layout_matches_gdbstub_arch() {
+        let mut len = 0;
+        CoreRegs::default().gdb_serialize(|_| len += 1);
+
+        assert_eq!(len, CORE_LEN + CORE_PADDING);
+        assert_eq!(described_size(&format!("{}{}", CORE_XML, SSE_XML)), CORE_LEN);
+        assert_eq!(described_regs(&format!("{}{}", CORE_XML, SSE_XML)), CORE_REGS);
+    }
+
+    #[test]
+    fn reg_ids_match_target_description() {
+        let regs = described_regs(&TARGET_XML);
+
+        assert!(X86RegId::from_raw_id(regs - 1).is_some());
+        assert!(X86RegId::from_raw_id(regs).is_none());
+    }
+
+    fn sample_regs() -> X86Regs {
+        let mut regs = X86Regs::default();
+
+        regs.core.eflags = 0x246;
+        regs.core.segments.cs = 0x33;
+        regs.core.xmm[1] = 0x0123_4567_89ab_cdef_fedc_ba98_7654_3210;
+        regs.core.mxcsr = 0x1f80;
+
+        for (i, (reg, val)) in SYSTEM_REGS.iter().zip(regs.system.iter_mut()).enumerate() {
+            *val = (0x1122_3344_5566_7788 + i as u64) & (u64::MAX >> (64 - reg.bits));
+        }
+
+        for (i, lanes) in regs.zmm.iter_mut().enumerate() {
+            for (j, lane) in lanes.iter_mut().enumerate() {
+                *lane = ((i as u128) << 64) | (j as u128 + 1);
+            }
+
+            // The lower lanes of the first registers are the SSE `xmm` registers
+            if i < XMM_REGS {
+                lanes[0] = 0;
+            }
+        }
+
+        for (i, k) in regs.k.iter_mut().enumerate() {
+            *k = 0x100 + i as u64;
+        }
+
+        regs
+    }
+
+    #[test]
+    fn serialized_size_matches_target_description() {
+        let mut len = 0;
+        sample_regs().gdb_serialize(|_| len += 1);
+
+        assert_eq!(len, described_size(&TARGET_XML));
+    }
+
+    #[test]
+    fn serialize_round_trip() {
+        let regs = sample_regs();
+
+        let mut bytes = Vec::new();
+        regs.gdb_serialize(|byte| bytes.push(byte.unwrap()));
+
+        let mut deserialized = X86Regs::default();
+        deserialized.gdb_deserialize(&bytes).unwrap();
+
+        assert_eq!(deserialized, regs);
+        assert!(X86Regs::default().gdb_deserialize(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
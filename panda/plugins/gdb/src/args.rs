@@ -3,17 +3,31 @@ use panda::prelude::*;
 #[derive(PandaArgs)]
 #[name = "gdb"]
 pub struct Args {
-    #[cfg(not(any(feature = "ppc", feature = "mips", feature = "mipsel", feature = "i386")))]
-    #[arg(default = "invalid")]
     pub file: String,
 
+    #[arg(default = 1)]
+    pub instance: u32,
+
     #[arg(default = 0)]
     pub base: u64,
 
+    #[arg(default = "127.0.0.1")]
+    pub host: String,
+
+    #[arg(default = 4444)]
+    pub port: u32,
+
+    pub unix_socket: String,
+
+    #[arg(default = "kernel")]
+    pub step_mode: String,
+
     pub ghidra_elf: bool,
     pub on_entry: bool,
     pub on_start: bool,
     pub absolute_addrs: bool,
+    pub system_wide: bool,
+    pub memory_map: bool,
 }
 
 impl Args {
@@ -0,0 +1,126 @@
+use panda::prelude::*;
+use panda::regs::Reg;
+
+use std::convert::TryInto;
+use std::fmt;
+
+/// A breakpoint condition, evaluated in the plugin so that breakpoints whose condition
+/// doesn't hold don't stop the guest
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Number(u64),
+    Register(Reg),
+    Deref(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(Box<Expr>, BinOp, Box<Expr>),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum BinOp {
+    Or,
+    And,
+    BitAnd,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+}
+
+/// The condition and counters associated with a breakpoint address
+#[derive(Clone, Debug, Default)]
+pub struct BreakCondition {
+    pub expr: Option<Expr>,
+    pub ignore_count: u64,
+    pub hit_count: u64,
+}
+
+impl Expr {
+    pub fn binary(lhs: Expr, op: BinOp, rhs: Expr) -> Self {
+        Expr::Binary(Box::new(lhs), op, Box::new(rhs))
+    }
+
+    /// Evaluate the expression, returns None if memory couldn't be read
+    pub fn eval(&self, cpu: &mut CPUState) -> Option<u64> {
+        Some(match self {
+            Expr::Number(num) => *num,
+            Expr::Register(reg) => panda::regs::get_reg(cpu, *reg) as u64,
+            Expr::Deref(addr) => {
+                let addr = addr.eval(cpu)? as target_ptr_t;
+                read_ptr(cpu, addr)? as u64
+            }
+            Expr::Not(expr) => (expr.eval(cpu)? == 0) as u64,
+            Expr::Binary(lhs, op, rhs) => {
+                let lhs = lhs.eval(cpu)?;
+
+                // Short circuit so memory isn't read needlessly
+                match op {
+                    BinOp::Or if lhs != 0 => return Some(1),
+                    BinOp::And if lhs == 0 => return Some(0),
+                    _ => (),
+                }
+
+                let rhs = rhs.eval(cpu)?;
+                match op {
+                    BinOp::Or | BinOp::And => (rhs != 0) as u64,
+                    BinOp::BitAnd => lhs & rhs,
+                    BinOp::Eq => (lhs == rhs) as u64,
+                    BinOp::Ne => (lhs != rhs) as u64,
+                    BinOp::Lt => (lhs < rhs) as u64,
+                    BinOp::Le => (lhs <= rhs) as u64,
+                    BinOp::Gt => (lhs > rhs) as u64,
+                    BinOp::Ge => (lhs >= rhs) as u64,
+                    BinOp::Add => lhs.wrapping_add(rhs),
+                    BinOp::Sub => lhs.wrapping_sub(rhs),
+                }
+            }
+        })
+    }
+}
+
+/// Read a pointer-sized value from guest memory
+pub(crate) fn read_ptr(cpu: &mut CPUState, addr: target_ptr_t) -> Option<target_ptr_t> {
+    let bytes = cpu.try_mem_read(addr, std::mem::size_of::<target_ptr_t>())?;
+    let bytes = bytes[..].try_into().ok()?;
+
+    #[cfg(any(feature = "ppc", feature = "mips", feature = "mips64"))]
+    let value = target_ptr_t::from_be_bytes(bytes);
+
+    #[cfg(not(any(feature = "ppc", feature = "mips", feature = "mips64")))]
+    let value = target_ptr_t::from_le_bytes(bytes);
+
+    Some(value)
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Number(num) => write!(f, "{:#x}", num),
+            Expr::Register(reg) => write!(f, "${}", reg),
+            Expr::Deref(addr) => write!(f, "*{}", addr),
+            Expr::Not(expr) => write!(f, "!{}", expr),
+            Expr::Binary(lhs, op, rhs) => write!(f, "({} {} {})", lhs, op, rhs),
+        }
+    }
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BinOp::Or => "||",
+            BinOp::And => "&&",
+            BinOp::BitAnd => "&",
+            BinOp::Eq => "==",
+            BinOp::Ne => "!=",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+        })
+    }
+}
@@ -1,14 +1,146 @@
+use gdbstub::conn::{Connection, ConnectionExt};
+
+use std::convert::TryInto;
 use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use crate::args::ARGS;
+
+/// A connection to gdb over one of the supported transports
+pub enum GdbConnection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+/// Block until gdb connects to the endpoint configured by the plugin arguments. Returns
+/// `None` after printing why if the endpoint can't be listened on or accepting fails.
+///
+/// Once listening, a line of the form `PANDA_GDB_ENDPOINT=tcp:<host>:<port>` or
+/// `PANDA_GDB_ENDPOINT=unix:<path>` is printed so wrapper scripts can find the endpoint.
+pub fn wait_for_gdb() -> Option<GdbConnection> {
+    if ARGS.unix_socket.is_empty() {
+        wait_for_tcp()
+    } else {
+        wait_for_unix(&ARGS.unix_socket)
+    }
+}
+
+fn wait_for_tcp() -> Option<GdbConnection> {
+    let port: u16 = match ARGS.port.try_into() {
+        Ok(port) => port,
+        Err(_) => {
+            eprintln!("[gdb] port {} must be in the range 0-65535", ARGS.port);
+            return None;
+        }
+    };
+
+    let listener = match TcpListener::bind((&ARGS.host[..], port)) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("[gdb] can't listen on {}:{}, {}", ARGS.host, port, err);
+            return None;
+        }
+    };
+
+    // Print the bound address rather than the requested one, as port 0 lets the OS pick
+    let addr = match listener.local_addr() {
+        Ok(addr) => addr,
+        Err(err) => {
+            eprintln!("[gdb] can't get the address listened on for {}:{}, {}", ARGS.host, port, err);
+            return None;
+        }
+    };
+    println!("Waiting for GDB connection on {}...", addr);
+    println!("PANDA_GDB_ENDPOINT=tcp:{}", addr);
+
+    match listener.accept() {
+        Ok((stream, _)) => {
+            println!("GDB client connected");
+            Some(GdbConnection::Tcp(stream))
+        }
+        Err(err) => {
+            eprintln!("[gdb] can't accept a connection on {}, {}", addr, err);
+            None
+        }
+    }
+}
+
+fn wait_for_unix(path: &str) -> Option<GdbConnection> {
+    // Clean up a socket left behind by a previous run, but never anything else
+    if let Ok(metadata) = std::fs::metadata(path) {
+        if metadata.file_type().is_socket() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    let listener = match UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("[gdb] can't listen on unix socket {}, {}", path, err);
+            return None;
+        }
+    };
+
+    println!("Waiting for GDB connection on {}...", path);
+    println!("PANDA_GDB_ENDPOINT=unix:{}", path);
+
+    match listener.accept() {
+        Ok((stream, _)) => {
+            println!("GDB client connected");
+            Some(GdbConnection::Unix(stream))
+        }
+        Err(err) => {
+            eprintln!("[gdb] can't accept a connection on unix socket {}, {}", path, err);
+            None
+        }
+    }
+}
+
+impl Connection for GdbConnection {
+    type Error = std::io::Error;
+
+    fn write(&mut self, byte: u8) -> Result<(), Self::Error> {
+        match self {
+            GdbConnection::Tcp(stream) => Connection::write(stream, byte),
+            GdbConnection::Unix(stream) => Connection::write(stream, byte),
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+        match self {
+            GdbConnection::Tcp(stream) => Connection::write_all(stream, buf),
+            GdbConnection::Unix(stream) => Connection::write_all(stream, buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        match self {
+            GdbConnection::Tcp(stream) => Connection::flush(stream),
+            GdbConnection::Unix(stream) => Connection::flush(stream),
+        }
+    }
+
+    fn on_session_start(&mut self) -> Result<(), Self::Error> {
+        match self {
+            GdbConnection::Tcp(stream) => Connection::on_session_start(stream),
+            GdbConnection::Unix(stream) => Connection::on_session_start(stream),
+        }
+    }
+}
+
+impl ConnectionExt for GdbConnection {
+    fn read(&mut self) -> Result<u8, Self::Error> {
+        match self {
+            GdbConnection::Tcp(stream) => ConnectionExt::read(stream),
+            GdbConnection::Unix(stream) => ConnectionExt::read(stream),
+        }
+    }
 
-pub fn wait_for_gdb() -> TcpStream {
-    println!("Waiting for GDB connection on port 4444...");
-    let listener = TcpListener::bind("127.0.0.1:4444")
-        .unwrap()
-        .incoming()
-        .next()
-        .unwrap()
-        .unwrap();
-    println!("GDB client connected");
-
-    listener
+    fn peek(&mut self) -> Result<Option<u8>, Self::Error> {
+        match self {
+            GdbConnection::Tcp(stream) => ConnectionExt::peek(stream),
+            GdbConnection::Unix(stream) => ConnectionExt::peek(stream),
+        }
+    }
 }
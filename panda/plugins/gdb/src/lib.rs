@@ -1,141 +1,373 @@
 #![allow(dead_code, unused_variables, unused_imports)]
 use panda::prelude::*;
-use gdbstub::GdbStub;
+use panda::plugins::osi::OSI;
+use panda::sys::target_pid_t;
+use gdbstub::stub::{GdbStub, DisconnectReason};
+use gdbstub::target::ext::breakpoints::WatchKind;
+use gdbstub::target::ext::catch_syscalls::CatchSyscallPosition;
 
-//use std::os::raw::{c_char, c_int};
-//use std::ffi::CStr;
+use std::os::raw::{c_char, c_void};
 
-#[cfg(not(any(feature = "aarch64", feature = "ppc")))]
 mod panda_target;
-#[cfg(not(any(feature = "aarch64", feature = "ppc")))]
-use panda_target::PandaTarget;
+use panda_target::{PandaTarget, PandaEventLoop};
 
-#[cfg(not(any(feature = "aarch64", feature = "ppc")))]
 mod target_state;
-#[cfg(not(any(feature = "aarch64", feature = "ppc")))]
-use target_state::{BreakStatus, STATE};
+use target_state::{BreakStatus, ReverseAction, STATE};
 
-#[cfg(not(any(feature = "aarch64", feature = "ppc")))]
 mod connection;
-#[cfg(not(any(feature = "aarch64", feature = "ppc")))]
 mod memory_map;
-#[cfg(not(any(feature = "aarch64", feature = "ppc")))]
+mod libraries;
+mod proc_start;
+mod syscalls;
+mod relocation;
+mod breakpoint_condition;
+mod registers;
+mod arch;
 mod monitor_commands;
 
-#[cfg(not(any(feature = "aarch64", feature = "ppc")))]
 mod args;
-#[cfg(not(any(feature = "aarch64", feature = "ppc")))]
-use args::Args;
+use args::ARGS;
 
-#[cfg(not(any(feature = "aarch64", feature = "ppc")))]
-lazy_static::lazy_static!{
-    static ref ARGS: Args = Args::from_panda_args();
-}
-
-#[cfg(not(any(feature = "aarch64", feature = "ppc")))]
 #[panda::init]
 fn init(_: &mut PluginHandle) -> bool {
     lazy_static::initialize(&ARGS);
     lazy_static::initialize(&STATE);
-    if ARGS.on_entry {
-        STATE.set_exit_kernel();
+    STATE.set_system_wide(ARGS.system_wide);
+    match &ARGS.step_mode[..] {
+        "user" => STATE.set_user_stepping(true),
+        "kernel" => (),
+        mode => eprintln!("[gdb] unknown step_mode '{}', expected 'user' or 'kernel'", mode),
+    }
+    if !ARGS.file.is_empty() {
+        STATE.set_wait_for_program(&ARGS.file, ARGS.instance);
+    } else if ARGS.on_entry {
+        STATE.set_wait_for_entry();
     }
 
     if ARGS.on_start {
-        let connection = connection::wait_for_gdb();
+        let connection = match connection::wait_for_gdb() {
+            Some(connection) => connection,
+            None => return false,
+        };
         STATE.start_single_stepping();
 
-        // Debugger runs in a seperate thread
-        std::thread::spawn(||{
-            let mut debugger = GdbStub::new(connection);
-            debugger.run(&mut PandaTarget)
-        });    
+        spawn_debugger(connection);
     }
 
     true
 }
 
-#[cfg(not(any(feature = "aarch64", feature = "ppc")))]
+/// Scope breakpoints and stepping to the process the CPU stopped in, unless a process
+/// has already been attached to or the kernel is running
+fn debug_current_process() {
+    if STATE.is_pid_set() {
+        return;
+    }
+
+    let cpu = STATE.wait_for_cpu();
+    let process = OSI.get_current_process(cpu);
+    if process.pid != 0 {
+        STATE.attach(process.pid as _, process.asid as _);
+    }
+}
+
+/// Run gdb sessions in a seperate thread, starting with `connection`. The CPU must
+/// be about to break to the debugger.
+///
+/// When gdb detaches, the guest returns to free running and the next connection
+/// starts a new session.
+fn spawn_debugger(connection: connection::GdbConnection) {
+    std::thread::spawn(move || {
+        let mut connection = connection;
+
+        loop {
+            // Wait for the CPU to stop before handing control to gdb
+            if let BreakStatus::Exit = STATE.brk.wait_for() {
+                break
+            }
+
+            debug_current_process();
+
+            let debugger = GdbStub::new(connection);
+            match debugger.run_blocking::<PandaEventLoop>(&mut PandaTarget) {
+                Ok(DisconnectReason::Disconnect) | Ok(DisconnectReason::Kill) => (),
+                Ok(DisconnectReason::TargetExited(_)) | Ok(DisconnectReason::TargetTerminated(_)) => break,
+                Err(err) => {
+                    eprintln!("[gdb] session ended with an error: {:?}", err);
+                    break
+                }
+            }
+
+            STATE.detach();
+            println!("GDB client detached, guest is running freely");
+
+            // Leave the guest running freely if gdb can't connect again
+            connection = match connection::wait_for_gdb() {
+                Some(connection) => connection,
+                None => break,
+            };
+            STATE.start_single_stepping();
+        }
+    });
+}
+
 #[panda::pre_shutdown]
 fn on_shutdown() {
     STATE.brk.signal(BreakStatus::Exit);
 }
 
-//#[panda::on_process_end]
-//#[cfg(not(any(feature = "ppc", feature = "mips", feature = "mipsel", feature = "i386")))]
-//fn on_process_end(_cpu: &mut CPUState, _name: *const c_char, _asid: target_ulong, pid: c_int) {
-//    if STATE.get_pid() == Some(pid as _) {
-//        STATE.unset_pid();
-//        STATE.brk.signal(BreakStatus::Exit);
-//    }
-//}
-
-//#[panda::on_process_start]
-//#[cfg(not(any(feature = "ppc", feature = "mips", feature = "mipsel", feature = "i386")))]
-//fn on_process_start(_cpu: &mut CPUState, name: *const c_char, _asid: target_ulong, pid: c_int) {
-//    // If a process is already being debugged, don't attach another debugger
-//    if !STATE.is_pid_set() {
-//        let name = unsafe { CStr::from_ptr(name) }.to_string_lossy();
-//        if name == ARGS.file {
-//            println!("*****************************");
-//            println!("{} started, pid: {}", name, pid);
-//            println!("*****************************");
-//            STATE.set_pid(pid as _);
-//            STATE.start_single_stepping();
-//            let connection = connection::wait_for_gdb();
-//            // Debugger runs in a seperate thread
-//            std::thread::spawn(||{
-//                let mut debugger = GdbStub::new(connection);
-//                debugger.run(&mut PandaTarget)
-//            });
-//        }
-//    }
-//}
-
-#[cfg(not(any(feature = "aarch64", feature = "ppc")))]
+// Track threads as they are created and exit. hooks2 doesn't support every architecture,
+// on the rest threads are only discovered when they are running as the CPU breaks.
+#[cfg(not(any(feature = "aarch64", feature = "ppc", feature = "mips", feature = "mipsel", feature = "i386")))]
+#[panda::on_thread_start]
+fn on_thread_start(_: &mut CPUState, _: *const c_char, _: target_ulong, pid: target_pid_t, tid: target_pid_t) {
+    STATE.add_thread(pid, tid);
+}
+
+#[cfg(not(any(feature = "aarch64", feature = "ppc", feature = "mips", feature = "mipsel", feature = "i386")))]
+#[panda::on_thread_end]
+fn on_thread_end(_: &mut CPUState, _: *const c_char, _: target_ulong, pid: target_pid_t, tid: target_pid_t) {
+    STATE.remove_thread(pid, tid);
+}
+
+// Record what processes are started with. proc_start_linux doesn't support ppc or mips64.
+#[cfg(not(any(feature = "ppc", feature = "mips64")))]
+#[panda::on_rec_auxv]
+fn on_rec_auxv(cpu: &mut CPUState, _: &mut TranslationBlock, auxv: &panda::plugins::proc_start_linux::AuxvValues) {
+    proc_start::record(cpu, auxv);
+    STATE.set_entry_point(panda::current_asid(cpu), auxv.entry);
+}
+
+// Stop on system calls for `catch syscall` and `monitor catch_syscall`. Only registered
+// with syscalls2 once system calls are caught, see `syscalls::register_callbacks`.
+extern "C" fn on_sys_enter(cpu: &mut CPUState, pc: target_ulong, callno: target_ulong) {
+    syscall_hit(cpu, pc, callno, CatchSyscallPosition::Entry);
+}
+
+extern "C" fn on_sys_return(cpu: &mut CPUState, pc: target_ulong, callno: target_ulong) {
+    syscall_hit(cpu, pc, callno, CatchSyscallPosition::Return);
+}
+
+fn syscall_hit(cpu: &mut CPUState, pc: target_ptr_t, number: target_ulong, position: CatchSyscallPosition) {
+    if !STATE.catching_syscalls() || STATE.reversing() || !STATE.in_debugged_process(cpu) {
+        return;
+    }
+
+    // Argument filters are only checked on entry, the arguments may be gone by the return
+    if STATE.gdb_catches_syscall(number) {
+        break_to_debugger(cpu, pc, BreakStatus::Syscall { number, position });
+    } else if position == CatchSyscallPosition::Entry && STATE.syscall_catchpoint_hit(cpu, number) {
+        break_to_debugger(cpu, pc, BreakStatus::SyscallCatchpoint);
+    }
+}
+
 #[panda::insn_exec]
 fn every_instruction(cpu: &mut CPUState, pc: target_ptr_t) {
-    // Check if we have just exited the kernel while the user is waiting for kernel
-    // to exit
-    if STATE.exited_kernel(pc) {
-        // Once we exit the kernel start single stepping and stop watching for kernel to exit
-        STATE.unset_exit_kernel();
+    // Check if a program is about to run its first instruction while the user is waiting
+    // for one to start
+    if STATE.at_entry_point(cpu, pc) {
+        // Once it has started, start single stepping and stop watching for programs starting
+        STATE.unset_wait_for_entry();
         STATE.start_single_stepping();
 
+        let process = OSI.get_current_process(cpu);
+        println!("[gdb] {} started, pid: {}", process.get_name(), process.pid);
+
         memory_map::print(cpu);
 
-        let connection = connection::wait_for_gdb();
-        // Debugger runs in a seperate thread
-        std::thread::spawn(||{
-            let mut debugger = GdbStub::new(connection);
-            debugger.run(&mut PandaTarget)
-        });
+        match connection::wait_for_gdb() {
+            Some(connection) => spawn_debugger(connection),
+            None => STATE.stop_single_stepping(),
+        }
+    }
+
+    if STATE.take_skip_instruction(pc) {
+        return;
+    }
+
+    // gdb interrupted the guest, stop wherever it is, whether in the debugged process or
+    // not. Instructions run before a pending checkpoint restore don't count.
+    if !STATE.restore_pending() && STATE.take_interrupt() {
+        STATE.cancel_reverse();
+        break_to_debugger(cpu, pc, BreakStatus::Interrupt);
+        return;
+    }
+
+    if STATE.reversing() {
+        reverse_instruction(cpu, pc);
+        return;
+    }
+
+    if !STATE.in_debugged_process(cpu) {
+        return;
     }
 
     // Break if single stepping or if we hit a breakpoint
-    if STATE.single_stepping() || STATE.breakpoints_contain(pc) {
-        // Mark single step as completed
-        STATE.stop_single_stepping();
-        // Pass the CPU to the debugging thread
-        STATE.set_cpu(cpu);
-        STATE.set_pc(pc);
-        // Signal the process has breaked
-        STATE.brk.signal(BreakStatus::Break);
-        // Wait for the signal to begin running again
-        STATE.cont.wait_for();
-        // Revoke the CPU from the debugging thread
-        STATE.unset_cpu();
-    }
-}
-
-#[cfg(not(any(feature = "aarch64", feature = "ppc")))]
-#[panda::insn_translate]
-fn translate_instr(_: &mut CPUState, pc: target_ptr_t) -> bool {
-    // Only instrument the instruction if we might break on it
-    STATE.single_stepping() || STATE.breakpoints_contain(pc) || STATE.exited_kernel(pc)
+    if STATE.single_stepping() && STATE.step_stops_here(cpu) {
+        break_to_debugger(cpu, pc, BreakStatus::Step);
+    } else if STATE.breakpoints_contain(pc) && STATE.breakpoint_should_stop(cpu, pc) {
+        break_to_debugger(cpu, pc, BreakStatus::Break);
+    }
 }
 
-#[cfg(any(feature = "aarch64", feature = "ppc"))]
-fn init(_: &mut PluginHandle) -> bool {
-    true
+/// Handle an instruction while re-executing from a checkpoint to go backwards
+fn reverse_instruction(cpu: &mut CPUState, pc: target_ptr_t) {
+    // The rest of the block runs before the checkpoint is restored, ignore it
+    if STATE.restore_pending() {
+        return;
+    }
+
+    let count = cpu.rr_guest_instr_count;
+
+    match STATE.reverse_instruction(count) {
+        ReverseAction::Run => {
+            if STATE.in_debugged_process(cpu) {
+                if STATE.reverse_stepping() {
+                    if STATE.step_stops_here(cpu) {
+                        STATE.reverse_hit(count, BreakStatus::Step);
+                    }
+                } else if STATE.breakpoints_contain(pc) && STATE.breakpoint_condition_holds(cpu, pc) {
+                    STATE.reverse_hit(count, BreakStatus::Break);
+                }
+            }
+        }
+        ReverseAction::Break(status) => break_to_debugger(cpu, pc, status),
+        ReverseAction::Restore => unsafe {
+            panda::sys::cpu_exit(cpu);
+        },
+    }
+}
+
+/// Restore checkpoints requested by reverse execution. This happens between blocks,
+/// as restoring exits the CPU loop without returning. Also keeps track of the running
+/// CPU for interrupts from gdb.
+#[panda::before_block_exec]
+fn before_block(cpu: &mut CPUState, tb: &mut TranslationBlock) {
+    STATE.set_running_cpu(cpu);
+    STATE.entering_block(tb.pc);
+
+    if let Some(checkpoint) = STATE.take_restore() {
+        STATE.invalidate_translations();
+        unsafe {
+            panda::sys::panda_restore_by_num(checkpoint);
+        }
+    }
+}
+
+#[panda::virt_mem_after_read]
+fn on_mem_read(cpu: &mut CPUState, pc: target_ptr_t, addr: target_ptr_t, size: usize, _buf: *mut c_void) {
+    if !STATE.in_debugged_process(cpu) {
+        return;
+    }
+
+    if let Some((addr, kind)) = STATE.watchpoint_hit(addr, size, WatchKind::Read) {
+        watchpoint_hit(cpu, pc, BreakStatus::Watch { kind, addr });
+    }
+}
+
+#[panda::virt_mem_after_write]
+fn on_mem_write(cpu: &mut CPUState, pc: target_ptr_t, addr: target_ptr_t, size: usize, _buf: *mut c_void) {
+    if !STATE.in_debugged_process(cpu) {
+        return;
+    }
+
+    if let Some((addr, kind)) = STATE.watchpoint_hit(addr, size, WatchKind::Write) {
+        watchpoint_hit(cpu, pc, BreakStatus::Watch { kind, addr });
+    }
+}
+
+fn watchpoint_hit(cpu: &mut CPUState, pc: target_ptr_t, status: BreakStatus) {
+    if STATE.reversing() {
+        // Stepping backwards stops on the previous instruction regardless
+        if !STATE.restore_pending() && !STATE.reverse_stepping() {
+            STATE.reverse_hit(cpu.rr_guest_instr_count, status);
+        }
+    } else {
+        break_to_debugger(cpu, pc, status);
+    }
+}
+
+/// Hand the CPU over to the debugger thread and block until gdb resumes execution
+fn break_to_debugger(cpu: &mut CPUState, pc: target_ptr_t, status: BreakStatus) {
+    // Watchpoints and system calls stop part way through an instruction, everything
+    // else before it
+    let before_instruction = !matches!(
+        status,
+        BreakStatus::Watch { .. } | BreakStatus::Syscall { .. } | BreakStatus::SyscallCatchpoint
+    );
+
+    // Whether the block being executed was translated to count instructions, in which
+    // case the instruction the CPU stopped on has already been counted
+    let instruction_counted = counting_instructions();
+
+    // Mark single step as completed
+    STATE.stop_single_stepping();
+    // Let the debugger know which thread stopped. Nothing owned may be left in this frame
+    // when restarting the instruction below, as that longjmps out of it.
+    {
+        let thread = OSI.get_current_thread(cpu);
+        STATE.set_current_thread(thread.pid, thread.tid);
+    }
+    // Pass the CPU to the debugging thread
+    relocation::forget_runtime_image();
+    STATE.set_cpu(cpu);
+    STATE.set_pc(pc);
+    STATE.set_instr_count(cpu.rr_guest_instr_count);
+    // Signal the process has breaked
+    STATE.brk.signal(status);
+    // Wait for the signal to begin running again
+    STATE.cont.wait_for();
+    // Revoke the CPU from the debugging thread
+    STATE.unset_cpu();
+
+    // Retranslate the code around breakpoints gdb added or removed while stopped
+    let breakpoints_changed = STATE.invalidate_changed_breakpoints(cpu);
+    // A later breakpoint at the same address of a deleted one starts out unconditional
+    STATE.drop_stale_conditions();
+
+    // Translated code keeps some registers (most importantly the PC) outside of the
+    // CPU state until the end of the block, so restart execution at the (possibly new)
+    // PC for register writes to take effect. The instruction the CPU stopped on hasn't
+    // run yet, so it isn't counted and isn't broken on again.
+    if STATE.take_registers_written() && before_instruction {
+        panda::regs::set_pc(cpu, STATE.get_pc());
+        if instruction_counted && cpu.rr_guest_instr_count > 0 {
+            cpu.rr_guest_instr_count -= 1;
+        }
+        STATE.skip_instruction_at(STATE.get_pc());
+
+        // Exiting the block also takes care of any pending flush
+        STATE.take_flush_pending();
+
+        unsafe {
+            panda::sys::cpu_loop_exit_noexc(cpu);
+        }
+    }
+
+    // If breakpoints changed while stopped, exit the current chain of translation
+    // blocks so the requested flush takes effect before the next block runs. The
+    // same goes for a checkpoint to be restored.
+    if STATE.take_flush_pending() || breakpoints_changed || STATE.restore_pending() {
+        unsafe {
+            panda::sys::cpu_exit(cpu);
+        }
+    }
+}
+
+/// Whether translated code counts guest instructions, which PANDA only does while
+/// recording or replaying, or with precise PCs (e.g. while watchpoints are set)
+fn counting_instructions() -> bool {
+    unsafe {
+        panda::sys::rr_control.mode != panda::sys::RR_mode_RR_OFF || panda::sys::panda_update_pc
+    }
+}
+
+#[panda::insn_translate]
+fn translate_instr(cpu: &mut CPUState, pc: target_ptr_t) -> bool {
+    // Only instrument the instruction if we might break on it. Programs start in user mode.
+    STATE.single_stepping()
+        || STATE.reversing()
+        || STATE.interrupt_requested()
+        || STATE.breakpoints_contain(pc)
+        || (STATE.waiting_for_entry() && !panda::in_kernel_mode(cpu))
 }
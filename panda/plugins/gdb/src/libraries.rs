@@ -0,0 +1,310 @@
+//! The executable and shared libraries of the debugged process, for gdb to load their
+//! symbols with `qXfer:exec-file` and `qXfer:libraries-svr4`.
+
+use panda::prelude::*;
+use panda::plugins::osi::{OSI, OsiModule, OsiProc};
+use panda::sys::target_pid_t;
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::CStr;
+
+const ET_DYN: u64 = 3;
+const PT_LOAD: u64 = 1;
+const PT_DYNAMIC: u64 = 2;
+const PF_X: u64 = 1;
+const PF_W: u64 = 2;
+const DT_NULL: u64 = 0;
+const DT_DEBUG: u64 = 21;
+
+/// Program headers are at most 0x40 bytes, so there's at most 4 MiB of them to read
+const MAX_PHENTSIZE: u64 = 0x40;
+/// Bounds on the dynamic section and link map walked, in case they're garbage
+const MAX_DYNAMIC_ENTRIES: usize = 0x400;
+const MAX_LINK_MAPS: usize = 0x1000;
+
+/// What's needed from the headers of a loaded ELF file. Addresses are unrelocated.
+struct ElfLayout {
+    /// Position independent, i.e. loaded at an offset from its addresses
+    relocatable: bool,
+    is_64: bool,
+    big_endian: bool,
+    entry: u64,
+    /// The first loaded segment
+    first_load: Option<u64>,
+    /// The address and permissions (`p_flags`) of each loaded segment
+    loads: Vec<(u64, u64)>,
+    /// The dynamic section
+    dynamic: Option<u64>,
+}
+
+/// Path of the file a mapping is of, None for anonymous mappings
+pub(crate) fn mapping_file(mapping: &OsiModule) -> Option<String> {
+    if mapping.file.is_null() {
+        return None;
+    }
+
+    // SAFETY: ptr must be non-null (checked) and must be valid (can assume so due to OSI)
+    let file = unsafe { CStr::from_ptr(mapping.file) };
+
+    Some(file.to_string_lossy().into_owned())
+}
+
+/// The first mapping of the process's executable. The process name is the start of the
+/// executable's file name (truncated to 15 characters by Linux), otherwise the first file
+/// mapped is assumed to be the executable.
+pub(crate) fn exec_mapping<'a>(mappings: &'a [OsiModule], proc: &OsiProc) -> Option<&'a OsiModule> {
+    let name = proc.get_name();
+    let mut files = mappings.iter().filter(|mapping| !mapping.file.is_null());
+
+    files.clone()
+        .find(|mapping| {
+            mapping_file(mapping)
+                .map(|file| file.rsplit('/').next().unwrap_or("").starts_with(&*name))
+                .unwrap_or(false)
+        })
+        .or_else(|| files.next())
+}
+
+/// Path of the executable of the process `pid`, or of the debugged process if None
+pub(crate) fn exec_file(cpu: &mut CPUState, pid: Option<target_pid_t>) -> Option<String> {
+    let mut procs;
+    let mut current;
+    let proc = match pid {
+        Some(pid) => {
+            procs = OSI.get_processes(cpu);
+            procs.iter_mut().find(|proc| proc.pid == pid)?
+        }
+        None => {
+            current = OSI.get_current_process(cpu);
+            &mut *current
+        }
+    };
+
+    let mappings = OSI.get_mappings(cpu, proc);
+
+    exec_mapping(&mappings, proc).and_then(mapping_file)
+}
+
+/// Where the current process's executable is mapped
+pub(crate) fn exec_base(cpu: &mut CPUState) -> Option<target_ptr_t> {
+    let mut proc = OSI.get_current_process(cpu);
+    let mappings = OSI.get_mappings(cpu, &mut *proc);
+
+    exec_mapping(&mappings, &proc).map(|exec| exec.base)
+}
+
+/// The entry point of the executable mapped at `base`, from its ELF header
+pub(crate) fn exec_entry(cpu: &mut CPUState, base: target_ptr_t) -> Option<target_ptr_t> {
+    let elf = elf_layout(cpu, base)?;
+    if elf.relocatable {
+        let first_load = elf.first_load?;
+        Some(load_offset(base, first_load).wrapping_add(elf.entry as target_ptr_t))
+    } else {
+        Some(elf.entry as target_ptr_t)
+    }
+}
+
+/// The offsets to relocate the text and data sections of the current process's executable
+/// by. The executable's mappings are found by its file name, and its text and data segments
+/// by their permissions in its program headers, which have to be mapped where expected.
+pub(crate) fn exec_section_offsets(cpu: &mut CPUState) -> Result<(target_ptr_t, target_ptr_t), String> {
+    let mut proc = OSI.get_current_process(cpu);
+    let mappings = OSI.get_mappings(cpu, &mut *proc);
+
+    let exec = exec_mapping(&mappings, &proc)
+        .ok_or_else(|| format!("no executable is mapped by {} (pid {})", proc.get_name(), proc.pid))?;
+    let file = mapping_file(exec).unwrap_or_default();
+
+    let elf = elf_layout(cpu, exec.base)
+        .ok_or_else(|| format!("the ELF header of {} at {:#x} can't be read", file, exec.base))?;
+    let offset = match (elf.relocatable, elf.first_load) {
+        (true, Some(first_load)) => load_offset(exec.base, first_load),
+        (true, None) => return Err(format!("{} has no loaded segments", file)),
+        (false, _) => 0,
+    };
+
+    let segment_offset = |name: &str, wanted: fn(u64) -> bool| {
+        let &(vaddr, _) = elf.loads
+            .iter()
+            .find(|&&(_, flags)| wanted(flags))
+            .ok_or_else(|| format!("{} has no {} segment", file, name))?;
+
+        let addr = offset.wrapping_add(vaddr as target_ptr_t);
+        let mapped = mappings.iter().any(|mapping| {
+            mapping_file(mapping).as_ref() == Some(&file)
+                && (mapping.base..mapping.base.wrapping_add(mapping.size)).contains(&addr)
+        });
+
+        if mapped {
+            Ok(offset)
+        } else {
+            Err(format!("the {} segment of {} isn't mapped at {:#x}", name, file, addr))
+        }
+    };
+
+    let text = segment_offset("text", |flags| flags & PF_X != 0)?;
+    let data = segment_offset("data", |flags| flags & PF_W != 0 && flags & PF_X == 0)?;
+
+    Ok((text, data))
+}
+
+/// The offset a file whose first segment is mapped at `base` is loaded at
+fn load_offset(base: target_ptr_t, first_load: u64) -> target_ptr_t {
+    base.wrapping_sub((first_load & !0xfff) as target_ptr_t)
+}
+
+/// The shared libraries mapped by the debugged process in gdb's SVR4 library list format.
+/// The load address of a library is found from the ELF headers of its first mapping, and
+/// the executable itself isn't part of the list.
+pub(crate) fn libraries_svr4_xml(cpu: &mut CPUState) -> String {
+    let mut proc = OSI.get_current_process(cpu);
+    let mappings = OSI.get_mappings(cpu, &mut *proc);
+    let exec = exec_mapping(&mappings, &proc);
+    let exec_file = exec.and_then(mapping_file);
+    let link_maps = exec.and_then(|exec| link_maps(cpu, exec.base)).unwrap_or_default();
+
+    let mut seen = HashSet::new();
+    let mut xml = String::from(r#"<library-list-svr4 version="1.0">"#);
+
+    for mapping in mappings.iter() {
+        let file = match mapping_file(mapping) {
+            Some(file) => file,
+            None => continue,
+        };
+
+        if exec_file.as_ref() == Some(&file) || !seen.insert(file.clone()) {
+            continue;
+        }
+
+        if let Some(ElfLayout { first_load: Some(first_load), dynamic: Some(dynamic), .. }) = elf_layout(cpu, mapping.base) {
+            let l_addr = load_offset(mapping.base, first_load);
+            let l_ld = l_addr.wrapping_add(dynamic as target_ptr_t);
+
+            // Libraries the dynamic linker hasn't linked yet (or if its link map can't be
+            // read) are told apart by their dynamic section instead
+            let lm = link_maps.get(&l_ld).copied().unwrap_or(l_ld);
+
+            xml.push_str(&format!(
+                r#"<library name="{}" lm="{:#x}" l_addr="{:#x}" l_ld="{:#x}"/>"#,
+                xml_escape(&file), lm, l_addr, l_ld
+            ));
+        }
+    }
+
+    xml.push_str("</library-list-svr4>");
+    xml
+}
+
+/// The dynamic linker's `link_map` entries of the executable loaded at `base`, by the
+/// address of the dynamic section of their file. The link map is found through the
+/// `r_debug` structure pointed to by the executable's `DT_DEBUG` entry, which is only set
+/// once the dynamic linker has started. None if the executable isn't dynamically linked.
+fn link_maps(cpu: &mut CPUState, base: target_ptr_t) -> Option<HashMap<target_ptr_t, target_ptr_t>> {
+    let elf = elf_layout(cpu, base)?;
+    let offset = if elf.relocatable { load_offset(base, elf.first_load?) } else { 0 };
+    let dynamic = offset.wrapping_add(elf.dynamic? as target_ptr_t);
+
+    let word_size = if elf.is_64 { 8 } else { 4 };
+    let mut read_word = |addr: target_ptr_t| {
+        cpu.try_mem_read(addr, word_size)
+            .map(|bytes| read_uint(&bytes, elf.big_endian) as target_ptr_t)
+    };
+
+    // Each entry is a `d_tag` and a `d_val` word
+    let mut r_debug = None;
+    for entry in 0..MAX_DYNAMIC_ENTRIES {
+        let addr = dynamic.wrapping_add((entry * 2 * word_size) as target_ptr_t);
+        match read_word(addr)? as u64 {
+            DT_NULL => break,
+            DT_DEBUG => {
+                r_debug = Some(read_word(addr.wrapping_add(word_size as target_ptr_t))?);
+                break;
+            }
+            _ => (),
+        }
+    }
+
+    // `r_map` follows the `int r_version`, padded to a word
+    let mut lm = read_word(r_debug.filter(|&r_debug| r_debug != 0)?.wrapping_add(word_size as target_ptr_t))?;
+
+    // The entries start with `l_addr`, `l_name`, `l_ld` and `l_next`
+    let mut link_maps = HashMap::new();
+    while lm != 0 && link_maps.len() < MAX_LINK_MAPS {
+        let l_ld = read_word(lm.wrapping_add(2 * word_size as target_ptr_t))?;
+        if link_maps.insert(l_ld, lm).is_some() {
+            break;
+        }
+
+        lm = read_word(lm.wrapping_add(3 * word_size as target_ptr_t))?;
+    }
+
+    Some(link_maps)
+}
+
+/// The layout of the ELF file loaded at `base`. None if it isn't an ELF file.
+fn elf_layout(cpu: &mut CPUState, base: target_ptr_t) -> Option<ElfLayout> {
+    // Large enough for either ELF header
+    let header = cpu.try_mem_read(base, 0x40)?;
+    if header[..4] != *b"\x7fELF" {
+        return None;
+    }
+
+    let is_64 = header[4] == 2;
+    let big_endian = header[5] == 2;
+    let field = |bytes: &[u8], offset: usize, size: usize| read_uint(&bytes[offset..offset + size], big_endian);
+
+    let e_type = field(&header, 0x10, 2);
+    let (entry, phoff, phentsize, phnum) = if is_64 {
+        (field(&header, 0x18, 8), field(&header, 0x20, 8), field(&header, 0x36, 2), field(&header, 0x38, 2))
+    } else {
+        (field(&header, 0x18, 4), field(&header, 0x1c, 4), field(&header, 0x2a, 2), field(&header, 0x2c, 2))
+    };
+
+    // `phnum` is 16 bits, so bounding `phentsize` bounds the size read
+    if phentsize < if is_64 { 0x38 } else { 0x20 } || phentsize > MAX_PHENTSIZE {
+        return None;
+    }
+
+    let phdrs = cpu.try_mem_read(base.wrapping_add(phoff as target_ptr_t), (phentsize * phnum) as usize)?;
+
+    let mut loads = Vec::new();
+    let mut dynamic = None;
+    for phdr in phdrs.chunks_exact(phentsize as usize) {
+        let p_vaddr = if is_64 { field(phdr, 0x10, 8) } else { field(phdr, 8, 4) };
+        let p_flags = if is_64 { field(phdr, 4, 4) } else { field(phdr, 0x18, 4) };
+
+        match field(phdr, 0, 4) {
+            PT_LOAD => loads.push((p_vaddr, p_flags)),
+            PT_DYNAMIC => dynamic = Some(p_vaddr),
+            _ => (),
+        }
+    }
+
+    Some(ElfLayout {
+        relocatable: e_type == ET_DYN,
+        is_64,
+        big_endian,
+        entry,
+        first_load: loads.first().map(|&(vaddr, _)| vaddr),
+        loads,
+        dynamic,
+    })
+}
+
+/// Read an unsigned integer the size of `bytes`
+fn read_uint(bytes: &[u8], big_endian: bool) -> u64 {
+    let shift_in = |val: u64, &byte: &u8| val << 8 | byte as u64;
+
+    if big_endian {
+        bytes.iter().fold(0, shift_in)
+    } else {
+        bytes.iter().rev().fold(0, shift_in)
+    }
+}
+
+pub(crate) fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -60,3 +60,47 @@ pub(crate) fn print_to_gdb(cpu: &mut CPUState, mut out: impl std::fmt::Write) {
         outputln!(out, "{:x}-{:x} {:x}    {}", start, end, mapping.modd, name);
     }
 }
+
+/// Start of the kernel's half of the address space, which is described as a single region
+/// so gdb doesn't refuse to access kernel memory
+#[cfg(feature = "x86_64")]
+const KERNEL_START: target_ptr_t = 0xffff_8000_0000_0000;
+#[cfg(feature = "aarch64")]
+const KERNEL_START: target_ptr_t = 0xffff_0000_0000_0000;
+#[cfg(feature = "mips64")]
+const KERNEL_START: target_ptr_t = 0x8000_0000_0000_0000;
+#[cfg(any(feature = "i386", feature = "ppc"))]
+const KERNEL_START: target_ptr_t = 0xc000_0000;
+// Kernel modules are mapped below the kernel itself
+#[cfg(feature = "arm")]
+const KERNEL_START: target_ptr_t = 0xbf00_0000;
+#[cfg(any(feature = "mips", feature = "mipsel"))]
+const KERNEL_START: target_ptr_t = 0x8000_0000;
+
+/// The mappings of the current process as a gdb memory map. OSI doesn't provide the
+/// permissions of a mapping, and gdb's memory map can't describe them beyond read-only
+/// memory, so every mapping is RAM.
+pub(crate) fn memory_map_xml(cpu: &mut CPUState) -> String {
+    let mut proc = OSI.get_current_process(cpu);
+    let mappings = OSI.get_mappings(cpu, &mut *proc);
+
+    let mut xml = String::from(
+        r#"<?xml version="1.0"?><!DOCTYPE memory-map PUBLIC "+//IDN gnu.org//DTD GDB Memory Map V1.0//EN" "http://sourceware.org/gdb/gdb-memory-map.dtd"><memory-map>"#,
+    );
+
+    // Mappings in the kernel's half (e.g. the x86_64 vsyscall page) are part of its region
+    for mapping in mappings.iter().filter(|mapping| mapping.base < KERNEL_START) {
+        xml.push_str(&format!(
+            r#"<memory type="ram" start="{:#x}" length="{:#x}"/>"#,
+            mapping.base, mapping.size
+        ));
+    }
+
+    xml.push_str(&format!(
+        r#"<memory type="ram" start="{:#x}" length="{:#x}"/>"#,
+        KERNEL_START, target_ptr_t::MAX - KERNEL_START + 1
+    ));
+
+    xml.push_str("</memory-map>");
+    xml
+}
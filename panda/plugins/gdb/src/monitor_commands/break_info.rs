@@ -0,0 +1,40 @@
+use gdbstub::outputln;
+use tabwriter::{TabWriter, Alignment};
+
+use std::io::Write;
+
+use crate::target_state::STATE;
+
+pub(crate) fn print(mut out: impl std::fmt::Write) {
+    let conditions = STATE.breakpoint_conditions();
+
+    outputln!(out);
+
+    if conditions.is_empty() {
+        outputln!(out, "No breakpoints have been hit or given a condition");
+        outputln!(out);
+        return;
+    }
+
+    let output = Vec::new();
+    let mut output = TabWriter::new(output).padding(1).alignment(Alignment::Left);
+
+    let _ = writeln!(output, "Address\tHits\tIgnore\tCondition");
+    let _ = writeln!(output, "=======\t====\t======\t=========");
+
+    #[allow(unused_must_use)]
+    for (addr, condition) in conditions {
+        writeln!(
+            output,
+            "{:#x?}\t{}\t{}\t{}",
+            addr,
+            condition.hit_count,
+            condition.ignore_count,
+            condition.expr.map(|expr| expr.to_string()).unwrap_or_default()
+        );
+    }
+
+    let _ = output.flush();
+    let output = String::from_utf8(output.into_inner().unwrap()).unwrap();
+    outputln!(out, "{}", output);
+}
@@ -0,0 +1,21 @@
+use gdbstub::outputln;
+
+use crate::target_state::STATE;
+
+pub(crate) fn print(mut out: impl std::fmt::Write) {
+    let catchpoints = STATE.syscall_catchpoints();
+
+    outputln!(out);
+
+    if catchpoints.is_empty() {
+        outputln!(out, "No syscall catchpoints, add one with catch_syscall [syscall] [filters]");
+        outputln!(out);
+        return;
+    }
+
+    for (i, catchpoint) in catchpoints.iter().enumerate() {
+        outputln!(out, "{}: {} ({} hits)", i + 1, catchpoint, catchpoint.hit_count);
+    }
+
+    outputln!(out);
+}
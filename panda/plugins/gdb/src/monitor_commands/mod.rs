@@ -3,12 +3,16 @@ use panda::taint;
 
 use gdbstub::outputln;
 
+use crate::target_state::STATE;
+
 mod parser;
 use parser::{Command, TaintTarget};
 
 mod thread_info;
 mod proc_info;
 mod proc_list;
+mod break_info;
+mod catch_info;
 
 pub(crate) fn handle_command(cmd: &str, cpu: &mut CPUState, mut out: impl std::fmt::Write) {
     let cmd = cmd.trim();
@@ -63,6 +67,65 @@ pub(crate) fn handle_command(cmd: &str, cpu: &mut CPUState, mut out: impl std::f
         Ok(Command::ThreadInfo) => thread_info::print(cpu, out),
         Ok(Command::ProcInfo) => proc_info::print(cpu, out),
         Ok(Command::ProcList) => proc_list::print(cpu, out),
+        Ok(Command::SystemWide(enabled)) => {
+            if let Some(enabled) = enabled {
+                STATE.set_system_wide(enabled);
+            }
+
+            if STATE.is_system_wide() {
+                outputln!(out, "Breakpoints and stepping apply to every process");
+            } else {
+                match STATE.get_pid() {
+                    Some(pid) => outputln!(out, "Breakpoints and stepping apply to pid {}", pid),
+                    None => outputln!(out, "Breakpoints and stepping apply to every process until one is attached"),
+                }
+            }
+        },
+        Ok(Command::StepMode(user)) => {
+            if let Some(user) = user {
+                STATE.set_user_stepping(user);
+            }
+
+            if STATE.is_user_stepping() {
+                outputln!(out, "Stepping skips kernel code, stopping at the next user instruction");
+            } else {
+                outputln!(out, "Stepping steps into kernel code");
+            }
+        },
+        Ok(Command::Condition(addr, expr)) => {
+            let addr = crate::relocation::to_runtime(cpu, addr);
+            match &expr {
+                Some(expr) => outputln!(out, "Breakpoint at {:#x?} stops when {}", addr, expr),
+                None => outputln!(out, "Breakpoint at {:#x?} is now unconditional", addr),
+            }
+            STATE.set_breakpoint_condition(addr, expr);
+        },
+        Ok(Command::Ignore(addr, count)) => {
+            let addr = crate::relocation::to_runtime(cpu, addr);
+            STATE.set_breakpoint_ignore_count(addr, count);
+            outputln!(out, "Will ignore the next {} hits of breakpoint at {:#x?}", count, addr);
+        },
+        Ok(Command::BreakInfo) => break_info::print(out),
+        Ok(Command::CatchSyscall(syscall, filters)) => {
+            if cfg!(feature = "ppc") {
+                outputln!(out, "System calls can't be caught on ppc, syscalls2 doesn't support it");
+                return;
+            }
+
+            match crate::syscalls::SyscallCatchpoint::new(syscall, filters) {
+                Ok(catchpoint) => {
+                    outputln!(out, "Will stop on syscall {}", catchpoint);
+                    crate::syscalls::register_callbacks();
+                    STATE.add_syscall_catchpoint(catchpoint);
+                }
+                Err(err) => outputln!(out, "Error: {}", err),
+            }
+        },
+        Ok(Command::ClearSyscallCatchpoints) => {
+            STATE.clear_syscall_catchpoints();
+            outputln!(out, "Removed all syscall catchpoints");
+        },
+        Ok(Command::CatchInfo) => catch_info::print(out),
         Ok(Command::Help) => print_help_text(out),
         Err(peg::error::ParseError { location, expected }) => {
             outputln!(out);
@@ -99,4 +162,11 @@ fn print_help_text(mut out: impl std::fmt::Write) {
     outputln!(out, "  threadinfo - get info about threads of the current process");
     outputln!(out, "  procinfo - get info about the current process");
     outputln!(out, "  proclist - list all the currently running processes");
+    outputln!(out, "  system_wide [on|off] - break in every process rather than just the debugged one");
+    outputln!(out, "  step_mode [user|kernel] - skip over kernel code when stepping, or step into it");
+    outputln!(out, "  condition [address] [expression] - only stop at a breakpoint when the expression is true");
+    outputln!(out, "  ignore [address] [count] - don't stop at a breakpoint for the next count hits");
+    outputln!(out, "  breakinfo - list breakpoint conditions, hit counts and ignore counts");
+    outputln!(out, "  catch_syscall [syscall] [filters] - stop on a syscall whose arguments match the filters");
+    outputln!(out, "  catch_syscall [clear] - list or remove syscall catchpoints");
 }
@@ -1,6 +1,9 @@
 use panda::prelude::*;
 use panda::regs::Reg;
 
+use crate::breakpoint_condition::{Expr, BinOp};
+use crate::syscalls::{ArgOp, ArgRef, ArgValue, SyscallRef};
+
 use peg::{error::ParseError, str::LineCol};
 
 use std::convert::TryInto;
@@ -14,6 +17,14 @@ pub(crate) enum Command {
     ThreadInfo,
     ProcInfo,
     ProcList,
+    SystemWide(Option<bool>),
+    StepMode(Option<bool>),
+    Condition(target_ptr_t, Option<Expr>),
+    Ignore(target_ptr_t, u64),
+    BreakInfo,
+    CatchSyscall(SyscallRef, Vec<(ArgRef, ArgOp, ArgValue)>),
+    ClearSyscallCatchpoints,
+    CatchInfo,
 }
 
 impl Command {
@@ -37,6 +48,12 @@ peg::parser! {
             / proc_info()
             / proc_list()
             / thread_info()
+            / system_wide()
+            / step_mode()
+            / condition()
+            / ignore()
+            / break_info()
+            / catch_syscall()
             / help()
 
         rule help() -> Command
@@ -54,6 +71,117 @@ peg::parser! {
         rule thread_info() -> Command
             = "threadinfo" { Command::ThreadInfo }
 
+        // system_wide [on|off]
+        rule system_wide() -> Command
+            = "system_wide" enabled:(_ enabled:on_off() { enabled })? {
+                Command::SystemWide(enabled)
+            }
+
+        rule break_info() -> Command
+            = "breakinfo" { Command::BreakInfo }
+
+        // condition [address] [expression]
+        rule condition() -> Command
+            = "condition" _ addr:number() expr:(_ expr:expression() { expr })? {
+                Command::Condition(addr as target_ptr_t, expr)
+            }
+
+        // ignore [address] [count]
+        rule ignore() -> Command
+            = "ignore" _ addr:number() _ count:number() {
+                Command::Ignore(addr as target_ptr_t, count)
+            }
+
+        // catch_syscall [syscall] [filters], catch_syscall clear, or catch_syscall to list
+        // (example: catch_syscall openat filename contains "passwd" && flags != 0)
+        rule catch_syscall() -> Command
+            = "catch_syscall" _ "clear" { Command::ClearSyscallCatchpoints }
+            / "catch_syscall" _ syscall:syscall_ref() filters:(_ filters:(arg_filter() ++ (__ "&&" __)) { filters })? {
+                Command::CatchSyscall(syscall, filters.unwrap_or_default())
+            }
+            / "catch_syscall" { Command::CatchInfo }
+
+        rule syscall_ref() -> SyscallRef
+            = quiet!{
+                num:number() { SyscallRef::Number(num) }
+                / name:identifier() { SyscallRef::Name(name.to_owned()) }
+            }
+            / expected!("a syscall name or number")
+
+        rule arg_filter() -> (ArgRef, ArgOp, ArgValue)
+            = arg:arg_ref() __ op:$("==" / "!=") __ value:arg_value() {
+                (arg, if op == "==" { ArgOp::Eq } else { ArgOp::Ne }, value)
+            }
+            / arg:arg_ref() _ "contains" _ string:string() {
+                (arg, ArgOp::Contains, ArgValue::String(string))
+            }
+
+        // Either argN or the name of the argument
+        rule arg_ref() -> ArgRef
+            = quiet!{
+                "arg" index:$(['0'..='9']+) !['a'..='z' | 'A'..='Z' | '0'..='9' | '_'] {?
+                    index.parse()
+                        .map(ArgRef::Index)
+                        .map_err(|_| "invalid argument number")
+                }
+                / name:identifier() { ArgRef::Name(name.to_owned()) }
+            }
+            / expected!("an argument name or argN")
+
+        rule arg_value() -> ArgValue
+            = string:string() { ArgValue::String(string) }
+            / num:number() { ArgValue::Number(num) }
+
+        // A double quoted string, without escapes
+        rule string() -> String
+            = quiet!{ "\"" string:$([^ '"']*) "\"" { string.to_owned() } }
+            / expected!("a quoted string")
+
+        rule identifier() -> &'input str
+            = $(['a'..='z' | 'A'..='Z' | '_'] ['a'..='z' | 'A'..='Z' | '0'..='9' | '_']*)
+
+        // A C-like expression of registers, numbers and memory dereferences
+        // (example: $rdi == 0x10 && *($rsp + 8) != 0)
+        rule expression() -> Expr = precedence!{
+            x:(@) __ "||" __ y:@ { Expr::binary(x, BinOp::Or, y) }
+            --
+            x:(@) __ "&&" __ y:@ { Expr::binary(x, BinOp::And, y) }
+            --
+            x:(@) __ "&" !"&" __ y:@ { Expr::binary(x, BinOp::BitAnd, y) }
+            --
+            x:(@) __ "==" __ y:@ { Expr::binary(x, BinOp::Eq, y) }
+            x:(@) __ "!=" __ y:@ { Expr::binary(x, BinOp::Ne, y) }
+            --
+            x:(@) __ "<=" __ y:@ { Expr::binary(x, BinOp::Le, y) }
+            x:(@) __ "<" __ y:@ { Expr::binary(x, BinOp::Lt, y) }
+            x:(@) __ ">=" __ y:@ { Expr::binary(x, BinOp::Ge, y) }
+            x:(@) __ ">" __ y:@ { Expr::binary(x, BinOp::Gt, y) }
+            --
+            x:(@) __ "+" __ y:@ { Expr::binary(x, BinOp::Add, y) }
+            x:(@) __ "-" __ y:@ { Expr::binary(x, BinOp::Sub, y) }
+            --
+            "!" __ x:@ { Expr::Not(Box::new(x)) }
+            "*" __ x:@ { Expr::Deref(Box::new(x)) }
+            --
+            num:number() { Expr::Number(num) }
+            "$"? reg:register() { Expr::Register(reg) }
+            "(" __ expr:expression() __ ")" { expr }
+        }
+
+        // step_mode [user|kernel]
+        rule step_mode() -> Command
+            = "step_mode" user:(_ user:user_kernel() { user })? {
+                Command::StepMode(user)
+            }
+
+        rule user_kernel() -> bool
+            = quiet!{ "user" { true } / "kernel" { false } }
+            / expected!("user or kernel")
+
+        rule on_off() -> bool
+            = quiet!{ "on" { true } / "off" { false } }
+            / expected!("on or off")
+
         // taint [target] [label]
         rule taint() -> Command
             = "taint" _ target:taint_target() _ label:number() {
@@ -100,5 +228,8 @@ peg::parser! {
 
         // rule for matching against arbitrary whitespace
         rule _() = quiet!{ [' ' | '\n' | '\t']+ }
+
+        // rule for matching against optional whitespace
+        rule __() = quiet!{ [' ' | '\n' | '\t']* }
     }
 }
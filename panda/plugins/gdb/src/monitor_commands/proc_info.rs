@@ -14,5 +14,34 @@ pub(crate) fn print(cpu: &mut CPUState, mut out: impl std::fmt::Write) {
     outputln!(out, "Parent PID: {}", proc.ppid);
     outputln!(out, "Creation time: {}", proc.create_time);
     outputln!(out, "PC in shared library: {}", OSI.in_shared_object(cpu, &*proc));
+
+    // The same as what gdb is sent for `qOffsets`
+    if crate::relocation::enabled() {
+        outputln!(out, "Section offsets: none, addresses are relative to {:#x}", crate::args::ARGS.base_addr());
+    } else {
+        match crate::libraries::exec_section_offsets(cpu) {
+            Ok((text, data)) => outputln!(out, "Section offsets: text {:#x}, data {:#x}", text, data),
+            Err(err) => outputln!(out, "Section offsets: none, {}", err),
+        }
+    }
+
+    match crate::proc_start::current(cpu) {
+        Some(start) => {
+            outputln!(out, "Working directory ($PWD at exec): {}", start.pwd().unwrap_or("[unknown]"));
+            outputln!(out, "Entry point: {:#x}", start.entry);
+            outputln!(out);
+            outputln!(out, "Arguments:");
+            for (i, arg) in start.argv.iter().enumerate() {
+                outputln!(out, "  argv[{}] = {:?}", i, arg);
+            }
+            outputln!(out);
+            outputln!(out, "Environment:");
+            for var in &start.envp {
+                outputln!(out, "  {}", var);
+            }
+        }
+        None => outputln!(out, "Arguments and environment unknown, the process wasn't seen starting"),
+    }
+
     outputln!(out);
 }
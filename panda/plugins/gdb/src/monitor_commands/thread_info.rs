@@ -3,6 +3,8 @@ use panda::plugins::osi::OSI;
 
 use gdbstub::outputln;
 
+use crate::target_state::STATE;
+
 pub(crate) fn print(cpu: &mut CPUState, mut out: impl std::fmt::Write) {
     let thread = OSI.get_current_thread(cpu);
 
@@ -11,4 +13,9 @@ pub(crate) fn print(cpu: &mut CPUState, mut out: impl std::fmt::Write) {
     outputln!(out, "pid: {}", thread.pid);
     outputln!(out, "tid: {}", thread.tid);
     outputln!(out);
+    outputln!(out, "Known threads of pid {}", thread.pid);
+    for tid in STATE.threads_of(thread.pid) {
+        outputln!(out, "tid: {}", tid);
+    }
+    outputln!(out);
 }
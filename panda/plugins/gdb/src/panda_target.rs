@@ -1,46 +1,79 @@
-use crate::{monitor_commands, target_state::{STATE, BreakStatus}};
+use crate::{connection::GdbConnection, libraries, memory_map, monitor_commands, proc_start, registers, relocation, target_state::{STATE, BreakStatus, CaughtSyscalls}};
 use gdbstub::{
+    conn::ConnectionExt,
     target::{Target, TargetResult, TargetError, ext},
-    target::ext::base::singlethread::{
-        SingleThreadOps,
-        StopReason,
-        ResumeAction,
+    target::ext::base::multithread::{
+        MultiThreadBase,
+        MultiThreadResume,
+        MultiThreadSingleStep,
     },
+    target::ext::base::reverse_exec::{ReverseCont, ReverseStep, ReplayLogPosition},
+    target::ext::extended_mode::{AttachKind, ShouldTerminate, CurrentActivePid},
+    stub::{MultiThreadStopReason, run_blocking},
     arch::Arch,
+    common::{Pid, Signal, Tid},
     outputln,
 };
 
 use panda::plugins::osi::OSI;
+use panda::sys::target_pid_t;
+use panda::prelude::target_ulong;
 
+use std::collections::HashMap;
 use std::convert::TryInto;
+use std::ffi::CString;
+use std::sync::Mutex;
+use std::time::Duration;
 
 pub struct PandaTarget;
 
+#[cfg(any(feature = "x86_64", feature = "i386"))]
+use crate::arch::x86::{self, X86System};
+
+#[cfg(feature = "x86_64")]
+use gdbstub_arch::x86::reg::X86_64CoreRegs;
+
+// gdb's order of the general purpose registers
 #[cfg(feature = "x86_64")]
-use gdbstub_arch::x86::{X86_64_SSE as X86_64, reg::{X86_64CoreRegs, X86SegmentRegs, F80}};
+const X86_64_GPRS: [u32; 16] = {
+    use panda::sys::{R_EAX, R_EBX, R_ECX, R_EDX, R_ESP, R_EBP, R_ESI, R_EDI};
+
+    [R_EAX, R_EBX, R_ECX, R_EDX, R_ESI, R_EDI, R_EBP, R_ESP, 8, 9, 10, 11, 12, 13, 14, 15]
+};
 
 #[cfg(feature = "i386")]
-use gdbstub_arch::x86::{X86_SSE as X86, reg::{X86CoreRegs, F80, X86SegmentRegs}};
+use gdbstub_arch::x86::reg::X86CoreRegs;
 
 #[cfg(feature = "arm")]
-use gdbstub_arch::arm::{Armv4t, reg::ArmCoreRegs};
+use crate::arch::arm::{self, Arm};
 
-//#[cfg(feature = "ppc")]
-//use gdbstub_arch::ppc::{PowerPcAltivec32 as PowerPc, reg::{PowerPcCommonRegs as PowerPcCoreRegs}};
+#[cfg(feature = "arm")]
+use gdbstub_arch::arm::reg::ArmCoreRegs;
 
-#[cfg(any(feature = "mips", feature = "mipsel", feature = "mips64"))]
-use gdbstub_arch::mips::{Mips, reg::MipsCoreRegs};
+#[cfg(feature = "aarch64")]
+use gdbstub_arch::aarch64::{AArch64, reg::AArch64CoreRegs};
+
+#[cfg(feature = "ppc")]
+use gdbstub_arch::ppc::{PowerPcAltivec32, reg::{PowerPcCommonRegs as PowerPcCoreRegs}};
+
+#[cfg(feature = "ppc")]
+type PowerPc = PowerPcAltivec32<()>;
+
+#[cfg(any(feature = "mips", feature = "mipsel"))]
+use gdbstub_arch::mips::Mips;
+
+#[cfg(feature = "mips64")]
+use gdbstub_arch::mips::Mips64 as Mips;
 
-#[cfg(not(any(feature = "aarch64", feature = "ppc")))]
 impl Target for PandaTarget {
-    #[cfg(feature = "x86_64")]
-    type Arch = X86_64;
-    
-    #[cfg(feature = "i386")]
-    type Arch = X86;
+    #[cfg(any(feature = "x86_64", feature = "i386"))]
+    type Arch = X86System;
     
     #[cfg(feature = "arm")]
-    type Arch = Armv4t;
+    type Arch = Arm;
+
+    #[cfg(feature = "aarch64")]
+    type Arch = AArch64;
     
     #[cfg(feature = "ppc")]
     type Arch = PowerPc;
@@ -50,113 +83,246 @@ impl Target for PandaTarget {
 
     type Error = ();
 
-    fn base_ops(&mut self) -> ext::base::BaseOps<Self::Arch, Self::Error> {
-        ext::base::BaseOps::SingleThread(
+    fn base_ops(&mut self) -> ext::base::BaseOps<'_, Self::Arch, Self::Error> {
+        ext::base::BaseOps::MultiThread(
             self as _
         )
     }
 
-    fn breakpoints(&mut self) -> Option<ext::breakpoints::BreakpointsOps<Self>> {
+    fn support_breakpoints(&mut self) -> Option<ext::breakpoints::BreakpointsOps<'_, Self>> {
+        Some(self as _)
+    }
+
+    fn support_monitor_cmd(&mut self) -> Option<ext::monitor_cmd::MonitorCmdOps<'_, Self>> {
         Some(self as _)
     }
 
-    fn monitor_cmd(&mut self) -> Option<ext::monitor_cmd::MonitorCmdOps<Self>> {
+    fn support_extended_mode(&mut self) -> Option<ext::extended_mode::ExtendedModeOps<'_, Self>> {
         Some(self as _)
     }
 
-    fn section_offsets(&mut self) -> Option<ext::section_offsets::SectionOffsetsOps<Self>> {
+    fn support_section_offsets(&mut self) -> Option<ext::section_offsets::SectionOffsetsOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn support_exec_file(&mut self) -> Option<ext::exec_file::ExecFileOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn support_libraries_svr4(&mut self) -> Option<ext::libraries::LibrariesSvr4Ops<'_, Self>> {
+        Some(self)
+    }
+
+    // gdb only reads the memory map once per connection, so it is opt-in, see `memory_map`
+    fn support_memory_map(&mut self) -> Option<ext::memory_map::MemoryMapOps<'_, Self>> {
+        if crate::args::ARGS.memory_map {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    fn support_auxv(&mut self) -> Option<ext::auxv::AuxvOps<'_, Self>> {
+        Some(self)
+    }
+
+    // syscalls2 doesn't support ppc
+    #[cfg(not(feature = "ppc"))]
+    fn support_catch_syscalls(&mut self) -> Option<ext::catch_syscalls::CatchSyscallsOps<'_, Self>> {
         Some(self)
     }
 }
 
-// Implement the standard operations as a fake-single-thread target
-impl SingleThreadOps for PandaTarget {
-    fn resume(
-        &mut self,
-        action: ResumeAction,
-        _check_gdb_interrupt: ext::base::GdbInterrupt<'_>,
-    ) -> Result<StopReason<<Self::Arch as Arch>::Usize>, Self::Error> {
-        match action {
-            ResumeAction::Step => {
-                STATE.start_single_stepping();
-                STATE.cont.signal(());
-                Ok(
-                    match STATE.brk.wait_for() {
-                        BreakStatus::Break => StopReason::DoneStep,
-                        BreakStatus::Exit => StopReason::Exited(0),
-                    }
-                )
+type StopReason = MultiThreadStopReason<<<PandaTarget as Target>::Arch as Arch>::Usize>;
+type Registers = <<PandaTarget as Target>::Arch as Arch>::Registers;
+
+lazy_static::lazy_static! {
+    /// The registers of each thread as of the last time they were read while it was running
+    /// on the CPU, by tid
+    static ref SAVED_REGISTERS: Mutex<HashMap<target_pid_t, Registers>> = Mutex::new(HashMap::new());
+}
+
+/// How long to wait for the CPU to break before checking for an interrupt from gdb
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Drives the gdb session: waits for the CPU to break after the target is resumed,
+/// while watching the connection for gdb interrupting it
+pub enum PandaEventLoop {}
+
+impl run_blocking::BlockingEventLoop for PandaEventLoop {
+    type Target = PandaTarget;
+    type Connection = GdbConnection;
+    type StopReason = StopReason;
+
+    fn wait_for_stop_reason(
+        _target: &mut PandaTarget,
+        conn: &mut GdbConnection,
+    ) -> Result<
+        run_blocking::Event<StopReason>,
+        run_blocking::WaitForStopReasonError<(), std::io::Error>,
+    > {
+        let status = loop {
+            if let Some(status) = STATE.brk.wait_for_timeout(POLL_INTERVAL) {
+                break status;
             }
-            ResumeAction::Continue => {
-                STATE.cont.signal(());
-                Ok(
-                    match STATE.brk.wait_for() {
-                        BreakStatus::Break => StopReason::SwBreak,
-                        BreakStatus::Exit => StopReason::Exited(0),
-                    }
-                )
+
+            // Incoming data while running is gdb interrupting the target, which gdbstub
+            // passes to `on_interrupt`
+            let connection_err = run_blocking::WaitForStopReasonError::Connection;
+            if conn.peek().map_err(connection_err)?.is_some() {
+                let byte = conn.read().map_err(connection_err)?;
+                return Ok(run_blocking::Event::IncomingData(byte));
+            }
+        };
+
+        let tid = to_gdb_tid(STATE.get_current_thread().1);
+
+        Ok(run_blocking::Event::TargetStopped(
+            match status {
+                BreakStatus::Step => MultiThreadStopReason::DoneStep,
+                BreakStatus::Break => MultiThreadStopReason::SwBreak(tid),
+                BreakStatus::Watch { kind, addr } => MultiThreadStopReason::Watch {
+                    tid,
+                    kind,
+                    addr: relocation::to_static(STATE.wait_for_cpu(), addr),
+                },
+                BreakStatus::Syscall { number, position } => MultiThreadStopReason::CatchSyscall {
+                    tid: Some(tid),
+                    number,
+                    position,
+                },
+                // gdb ignores syscall stops it isn't catching, so report these as a trap
+                BreakStatus::SyscallCatchpoint => MultiThreadStopReason::SignalWithThread {
+                    tid,
+                    signal: Signal::SIGTRAP,
+                },
+                BreakStatus::Interrupt => MultiThreadStopReason::SignalWithThread {
+                    tid,
+                    signal: Signal::SIGINT,
+                },
+                BreakStatus::ReplayStart => MultiThreadStopReason::ReplayLog {
+                    tid: Some(tid),
+                    pos: ReplayLogPosition::Begin,
+                },
+                BreakStatus::Exit => MultiThreadStopReason::Exited(0),
             }
-            _ => panic!("signals not supported")
+        ))
+    }
+
+    // The CPU is stopped from its own thread, which reports it through `STATE.brk` like
+    // any other break
+    fn on_interrupt(_target: &mut PandaTarget) -> Result<Option<StopReason>, ()> {
+        STATE.request_interrupt();
+
+        Ok(None)
+    }
+}
+
+/// gdb reserves thread id 0, so the idle task is reported using an id which no real
+/// thread can have
+const IDLE_TID: usize = i32::MAX as usize;
+
+fn to_gdb_tid(tid: target_pid_t) -> Tid {
+    Tid::new(if tid == 0 { IDLE_TID } else { tid as usize }).unwrap()
+}
+
+fn from_gdb_tid(tid: Tid) -> target_pid_t {
+    if tid.get() == IDLE_TID { 0 } else { tid.get() as target_pid_t }
+}
+
+/// Only the registers of the thread currently running on the CPU are live
+fn is_current_thread(tid: Tid) -> bool {
+    STATE.get_current_thread().1 == from_gdb_tid(tid)
+}
+
+// Implement the standard operations as a multithreaded target, where the threads are those
+// of the process being debugged. Threads can't be resumed independently, the guest
+// scheduler decides what runs, so a step of any thread single steps the CPU.
+impl MultiThreadBase for PandaTarget {
+    fn support_resume(&mut self) -> Option<ext::base::multithread::MultiThreadResumeOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn list_active_threads(
+        &mut self,
+        thread_is_active: &mut dyn FnMut(Tid),
+    ) -> Result<(), Self::Error> {
+        let cpu = STATE.wait_for_cpu();
+        let pid = STATE.debugged_pid();
+
+        // OSI lists one process per thread group, and the tid of the group's leader is the
+        // pid, so the main thread is known even if the CPU hasn't stopped in it yet
+        if OSI.get_processes(cpu).iter().any(|process| process.pid == pid) {
+            STATE.add_thread(pid, pid);
         }
+
+        let current_tid = STATE.get_current_thread().1;
+        let threads = STATE.threads_of(pid);
+
+        // Threads which have exited may have their tid reused
+        SAVED_REGISTERS.lock().unwrap().retain(|tid, _| *tid == current_tid || threads.contains(tid));
+
+        // The thread the CPU stopped in must always be reported, even if it isn't part
+        // of the debugged process (e.g. a context switch while stepping)
+        if !threads.contains(&current_tid) {
+            thread_is_active(to_gdb_tid(current_tid));
+        }
+
+        for tid in threads {
+            thread_is_active(to_gdb_tid(tid));
+        }
+
+        Ok(())
     }
 
     fn read_registers(
         &mut self,
         regs: &mut <Self::Arch as Arch>::Registers,
+        tid: Tid,
     ) -> TargetResult<(), Self> {
+        // A thread which isn't running has its registers saved by the guest kernel, where
+        // they can't be found, so it reports those it had when it last ran on the CPU
+        if !is_current_thread(tid) {
+            return match SAVED_REGISTERS.lock().unwrap().get(&from_gdb_tid(tid)) {
+                Some(saved) => {
+                    regs.clone_from(saved);
+                    Ok(())
+                }
+                None => Err(TargetError::NonFatal),
+            };
+        }
 
         let cpu = STATE.wait_for_cpu();
+        let pc = relocation::to_static(cpu, STATE.get_pc());
 
         #[cfg(feature = "x86_64")] {
-            let env = unsafe { &*(cpu.env_ptr as *const panda::sys::CPUX86State) };
-
-            let segments: [u32; 6] = (&env.segs.iter().map(|seg| seg.base as u32)
-                .collect::<Vec<_>>()[..6])
-                .try_into()
-                .unwrap();
-
-            let segments = X86SegmentRegs {
-                cs: segments[0],
-                ss: segments[1],
-                ds: segments[2],
-                es: segments[3],
-                fs: segments[4],
-                gs: segments[5],
-            };
+            let env = unsafe { &mut *(cpu.env_ptr as *mut panda::sys::CPUX86State) };
 
-            *regs = X86_64CoreRegs {
-                eflags: env.eflags as _,
-                regs: (*env).regs.clone(),
-                rip: STATE.get_pc(),
-                segments,
-                st: (&env.fpregs.iter().map(fpreg_to_bytes).collect::<Vec<_>>()[..8]).try_into().unwrap(),
-                xmm: (&env.xmm_regs.iter().map(zmm_to_xmm).collect::<Vec<_>>()[..16]).try_into().unwrap(),
+            regs.system = x86::read_system_regs(env);
+            regs.zmm = x86::read_zmm(env);
+            regs.k = env.opmask_regs;
+            regs.core = X86_64CoreRegs {
+                regs: X86_64_GPRS.map(|i| env.regs[i as usize]),
+                eflags: registers::x86_eflags(env),
+                rip: pc,
+                segments: registers::x86_segments(env),
+                st: registers::x86_st(env),
+                fpu: registers::x86_fpu(env),
+                xmm: registers::x86_xmm(env, 16).try_into().unwrap(),
                 mxcsr: env.mxcsr,
-                ..Default::default()
             };
         }
         
         #[cfg(feature = "i386")] {
-            let env = unsafe { &*(cpu.env_ptr as *const panda::sys::CPUX86State) };
+            let env = unsafe { &mut *(cpu.env_ptr as *mut panda::sys::CPUX86State) };
 
             use panda::sys::{R_EAX, R_EBX, R_ECX, R_EDX, R_ESP, R_EBP, R_ESI, R_EDI};
 
-            let segments: [u32; 6] = (&env.segs.iter().map(|seg| seg.base as u32)
-                .collect::<Vec<_>>()[..6])
-                .try_into()
-                .unwrap();
-
-            let segments = X86SegmentRegs {
-                cs: segments[0],
-                ss: segments[1],
-                ds: segments[2],
-                es: segments[3],
-                fs: segments[4],
-                gs: segments[5],
-            };
-
-            *regs = X86CoreRegs {
-                eflags: env.eflags,
+            regs.system = x86::read_system_regs(env);
+            regs.zmm = x86::read_zmm(env);
+            regs.k = env.opmask_regs;
+            regs.core = X86CoreRegs {
+                eflags: registers::x86_eflags(env),
                 eax: env.regs[R_EAX as usize],
                 ebx: env.regs[R_EBX as usize],
                 ecx: env.regs[R_ECX as usize],
@@ -165,77 +331,180 @@ impl SingleThreadOps for PandaTarget {
                 ebp: env.regs[R_EBP as usize],
                 esi: env.regs[R_ESI as usize],
                 edi: env.regs[R_EDI as usize],
-                eip: STATE.get_pc(),
-                segments,
-                st: (&(*env).fpregs.iter().map(fpreg_to_bytes).collect::<Vec<_>>()[..8]).try_into().unwrap(),
-                xmm: (&(*env).xmm_regs.iter().map(zmm_to_xmm).collect::<Vec<_>>()[..8]).try_into().unwrap(),
-                mxcsr: (*env).mxcsr,
-                ..Default::default()
+                eip: pc,
+                segments: registers::x86_segments(env),
+                st: registers::x86_st(env),
+                fpu: registers::x86_fpu(env),
+                xmm: registers::x86_xmm(env, 8).try_into().unwrap(),
+                mxcsr: env.mxcsr,
             };
         }
         
         #[cfg(feature = "arm")] {
-            let env = unsafe { &*(cpu.env_ptr as *const panda::sys::CPUARMState) };
+            let env = unsafe { &mut *(cpu.env_ptr as *mut panda::sys::CPUARMState) };
 
-            *regs = ArmCoreRegs {
+            regs.core = ArmCoreRegs {
                 r: env.regs[0..13].try_into().unwrap(),
                 sp: env.regs[13],
                 lr: env.regs[14],
-                pc: STATE.get_pc(),
-                cpsr: env.uncached_cpsr,
-                ..Default::default()
+                pc,
+                cpsr: unsafe { panda::sys::cpsr_read(env) },
+            };
+
+            regs.d.copy_from_slice(&env.vfp.regs[..32]);
+            regs.fpscr = unsafe { panda::sys::vfp_get_fpscr(env) };
+            regs.banked = arm::read_banked_regs(env);
+        }
+
+        #[cfg(feature = "aarch64")] {
+            let env = unsafe { &mut *(cpu.env_ptr as *mut panda::sys::CPUARMState) };
+
+            // Vn is made up of the two doublewords regs[2n] (low) and regs[2n + 1] (high)
+            let mut v = [0u128; 32];
+            for (i, v) in v.iter_mut().enumerate() {
+                *v = (env.vfp.regs[2 * i + 1] as u128) << 64 | env.vfp.regs[2 * i] as u128;
+            }
+
+            let fpscr = unsafe { panda::sys::vfp_get_fpscr(env) };
+
+            *regs = AArch64CoreRegs {
+                x: env.xregs[0..31].try_into().unwrap(),
+                sp: env.xregs[31],
+                pc,
+                cpsr: aarch64_pstate_read(env),
+                v,
+                fpcr: fpscr & FPCR_MASK,
+                fpsr: fpscr & FPSR_MASK,
             };
         }
 
         #[cfg(feature = "ppc")] {
+            let env = unsafe { &mut *(cpu.env_ptr as *mut panda::sys::CPUPPCState) };
+
+            // CR is stored as 8 separate 4-bit fields, CR0 being the most significant
+            let cr = env.crf.iter().fold(0, |cr, &field| (cr << 4) | (field & 0xf));
+
+            let mut vr = [0u128; 32];
+            for (vr, avr) in vr.iter_mut().zip(env.avr.iter()) {
+                let avr = unsafe { avr.u64 };
+                *vr = (avr[1] as u128) << 64 | avr[0] as u128;
+            }
 
+            *regs = PowerPcCoreRegs {
+                r: env.gpr,
+                f: env.fpr.map(f64::from_bits),
+                pc,
+                msr: env.msr,
+                cr,
+                lr: env.lr,
+                ctr: env.ctr,
+                xer: unsafe { panda::sys::cpu_read_xer(env) },
+                fpscr: env.fpscr,
+                vr,
+                vscr: env.vscr,
+                vrsave: env.spr[panda::sys::SPR_VRSAVE as usize],
+            };
         }
         
-        #[cfg(any(feature = "mips", feature = "mipsel"))] {
+        #[cfg(any(feature = "mips", feature = "mipsel", feature = "mips64"))] {
+            use panda::sys::{CP0C1_FP, CP0St_FR, MIPS_HFLAG_M16};
+
             let env = unsafe { &*(cpu.env_ptr as *const panda::sys::CPUMIPSState) };
 
             regs.r = env.active_tc.gpr;
             regs.lo = env.active_tc.LO[0];
             regs.hi = env.active_tc.HI[0];
-            regs.pc = STATE.get_pc();
-            regs.cp0.status = env.CP0_Status as _;
-            regs.cp0.badvaddr = env.CP0_BadVAddr as _;
-            regs.cp0.cause = env.CP0_Cause as _;
-            // TODO: fpu
+
+            // The low bit of the PC marks MIPS16 mode
+            regs.pc = pc | (env.hflags & MIPS_HFLAG_M16 != 0) as target_ulong;
+
+            // 32-bit CP0 registers are sign extended, same as QEMU's gdbstub
+            regs.cp0.status = env.CP0_Status as target_ulong;
+            regs.cp0.badvaddr = env.CP0_BadVAddr;
+            regs.cp0.cause = env.CP0_Cause as target_ulong;
+
+            if env.CP0_Config1 & (1 << CP0C1_FP) != 0 {
+                let fpu = &env.active_fpu;
+                let fr = env.CP0_Status & (1 << CP0St_FR) != 0;
+
+                // Without Status.FR only the low word of each FPR is in use
+                for (reg, fpr) in regs.fpu.r.iter_mut().zip(fpu.fpr.iter()) {
+                    *reg = unsafe {
+                        if fr { fpr.d as target_ulong } else { fpr.w[0] as target_ulong }
+                    };
+                }
+
+                regs.fpu.fcsr = fpu.fcr31 as i32 as target_ulong;
+                regs.fpu.fir = fpu.fcr0 as i32 as target_ulong;
+            }
         }
 
+        SAVED_REGISTERS.lock().unwrap().insert(from_gdb_tid(tid), regs.clone());
+
         Ok(())
     }
 
+    // Only registers which differ from their current value are written, as writing some
+    // registers has side effects in QEMU (e.g. switching register banks), and gdb writes
+    // the whole register file to change a single register if `P` isn't supported.
+    //
+    // The PC is kept in `STATE` and written to the CPU when it resumes, see `break_to_debugger`.
     fn write_registers(
         &mut self,
         regs: &<Self::Arch as Arch>::Registers,
+        tid: Tid,
     ) -> TargetResult<(), Self> {
+        // Saved registers of threads which aren't running can't be written back
+        if !is_current_thread(tid) {
+            return Err(TargetError::NonFatal);
+        }
+
+        let mut old = Default::default();
+        self.read_registers(&mut old, tid)?;
+
+        if *regs == old {
+            return Ok(());
+        }
+
         let cpu = STATE.wait_for_cpu();
 
         #[cfg(feature = "x86_64")] {
-            let env = cpu.env_ptr as *mut panda::sys::CPUX86State;
+            let env = unsafe { &mut *(cpu.env_ptr as *mut panda::sys::CPUX86State) };
 
-            unsafe {
-                (*env).regs = regs.regs.clone();
-                (*env).eip = regs.rip;
-                (*env).mxcsr = regs.mxcsr;
+            x86::write_system_regs(env, &regs.system, &old.system);
+            x86::write_zmm(env, &regs.zmm);
+            env.opmask_regs = regs.k;
+            let (regs, old) = (&regs.core, &old.core);
+
+            for (&i, &val) in X86_64_GPRS.iter().zip(regs.regs.iter()) {
+                env.regs[i as usize] = val;
             }
-        }
-        #[cfg(feature = "arm")] {
-            let env = unsafe { &mut *(cpu.env_ptr as *mut panda::sys::CPUARMState) };
-            
-            for i in 0..13 {
-                env.regs[i] = regs.r[i];
+
+            STATE.set_pc(relocation::to_runtime(cpu, regs.rip));
+
+            if regs.eflags != old.eflags {
+                registers::x86_load_eflags(env, regs.eflags);
+            }
+
+            registers::x86_load_segments(env, &regs.segments);
+            registers::x86_load_st(env, &regs.st);
+            registers::x86_load_fpu(env, &regs.fpu, &old.fpu);
+            registers::x86_load_xmm(env, &regs.xmm);
+
+            if regs.mxcsr != old.mxcsr {
+                unsafe {
+                    panda::sys::cpu_set_mxcsr(env, regs.mxcsr);
+                }
             }
-            env.regs[13] = regs.sp;
-            env.regs[14] = regs.lr;
-            env.regs[15] = regs.pc;
-            env.uncached_cpsr = regs.cpsr;
         }
         #[cfg(feature = "i386")] {
             let env = unsafe { &mut *(cpu.env_ptr as *mut panda::sys::CPUX86State) };
 
+            x86::write_system_regs(env, &regs.system, &old.system);
+            x86::write_zmm(env, &regs.zmm);
+            env.opmask_regs = regs.k;
+            let (regs, old) = (&regs.core, &old.core);
+
             use panda::sys::{R_EAX, R_EBX, R_ECX, R_EDX, R_ESP, R_EBP, R_ESI, R_EDI};
 
             for &(i, val) in &[
@@ -251,23 +520,184 @@ impl SingleThreadOps for PandaTarget {
                 env.regs[i as usize] = val;
             }
 
-            env.eflags = regs.eflags;
-            STATE.set_pc(regs.eip);
+            STATE.set_pc(relocation::to_runtime(cpu, regs.eip));
+
+            if regs.eflags != old.eflags {
+                registers::x86_load_eflags(env, regs.eflags);
+            }
+
+            registers::x86_load_segments(env, &regs.segments);
+            registers::x86_load_st(env, &regs.st);
+            registers::x86_load_fpu(env, &regs.fpu, &old.fpu);
+            registers::x86_load_xmm(env, &regs.xmm);
+
+            if regs.mxcsr != old.mxcsr {
+                unsafe {
+                    panda::sys::cpu_set_mxcsr(env, regs.mxcsr);
+                }
+            }
         }
+        #[cfg(feature = "arm")] {
+            let env = unsafe { &mut *(cpu.env_ptr as *mut panda::sys::CPUARMState) };
 
-        Ok(())
+            env.vfp.regs[..32].copy_from_slice(&regs.d);
+            if regs.fpscr != old.fpscr {
+                unsafe {
+                    panda::sys::vfp_set_fpscr(env, regs.fpscr);
+                }
+            }
+
+            // The banked registers are read-only, see `arch::arm`
+            let (regs, old) = (&regs.core, &old.core);
+
+            // Changing the mode in CPSR switches register banks, so it's written first
+            // for the other registers to end up in the new mode's bank
+            if regs.cpsr != old.cpsr {
+                unsafe {
+                    panda::sys::cpsr_write(
+                        env,
+                        regs.cpsr,
+                        0xffffffff,
+                        panda::sys::CPSRWriteType_CPSRWriteByGDBStub,
+                    );
+                }
+            }
+
+            env.regs[0..13].copy_from_slice(&regs.r);
+            env.regs[13] = regs.sp;
+            env.regs[14] = regs.lr;
+
+            // The low bit of the PC is masked out the same as QEMU's gdbstub does
+            STATE.set_pc(relocation::to_runtime(cpu, regs.pc & !1));
+        }
+        #[cfg(feature = "aarch64")] {
+            let env = unsafe { &mut *(cpu.env_ptr as *mut panda::sys::CPUARMState) };
+
+            env.xregs[0..31].copy_from_slice(&regs.x);
+            env.xregs[31] = regs.sp;
+            STATE.set_pc(relocation::to_runtime(cpu, regs.pc));
+            aarch64_pstate_write(env, regs.cpsr);
+
+            for (i, v) in regs.v.iter().enumerate() {
+                env.vfp.regs[2 * i] = *v as u64;
+                env.vfp.regs[2 * i + 1] = (*v >> 64) as u64;
+            }
+
+            if (regs.fpcr, regs.fpsr) != (old.fpcr, old.fpsr) {
+                unsafe {
+                    panda::sys::vfp_set_fpscr(env, (regs.fpcr & FPCR_MASK) | (regs.fpsr & FPSR_MASK));
+                }
+            }
+        }
+        #[cfg(feature = "ppc")] {
+            let env = unsafe { &mut *(cpu.env_ptr as *mut panda::sys::CPUPPCState) };
+
+            env.gpr = regs.r;
+            env.fpr = regs.f.map(f64::to_bits);
+            STATE.set_pc(relocation::to_runtime(cpu, regs.pc));
+            env.lr = regs.lr;
+            env.ctr = regs.ctr;
+            env.vscr = regs.vscr;
+            env.spr[panda::sys::SPR_VRSAVE as usize] = regs.vrsave;
+
+            for (i, crf) in env.crf.iter_mut().enumerate() {
+                *crf = (regs.cr >> (28 - 4 * i)) & 0xf;
+            }
+
+            for (avr, vr) in env.avr.iter_mut().zip(regs.vr.iter()) {
+                avr.u64 = [*vr as u64, (*vr >> 64) as u64];
+            }
+
+            // MSR, XER and FPSCR have side effects (or are partially stored elsewhere),
+            // so go through QEMU to update them
+            unsafe {
+                if regs.msr != old.msr {
+                    panda::sys::ppc_store_msr(env, regs.msr);
+                }
+                if regs.xer != old.xer {
+                    panda::sys::cpu_write_xer(env, regs.xer);
+                }
+                if regs.fpscr != old.fpscr {
+                    panda::sys::helper_store_fpscr(env, regs.fpscr as u64, 0xffffffff);
+                }
+            }
+        }
+        #[cfg(any(feature = "mips", feature = "mipsel", feature = "mips64"))] {
+            use panda::sys::{CP0C1_FP, CP0St_FR, MIPS_HFLAG_M16};
+
+            let env = unsafe { &mut *(cpu.env_ptr as *mut panda::sys::CPUMIPSState) };
+
+            env.active_tc.gpr = regs.r;
+            env.active_tc.LO[0] = regs.lo;
+            env.active_tc.HI[0] = regs.hi;
+            env.CP0_BadVAddr = regs.cp0.badvaddr;
+
+            STATE.set_pc(relocation::to_runtime(cpu, regs.pc & !1));
+            if regs.pc & 1 != 0 {
+                env.hflags |= MIPS_HFLAG_M16;
+            } else {
+                env.hflags &= !MIPS_HFLAG_M16;
+            }
+
+            unsafe {
+                if regs.cp0.status != old.cp0.status {
+                    panda::sys::cpu_mips_store_status(env, regs.cp0.status);
+                }
+                if regs.cp0.cause != old.cp0.cause {
+                    panda::sys::cpu_mips_store_cause(env, regs.cp0.cause);
+                }
+            }
+
+            // Status.FR may have just changed, so check it after storing Status
+            if env.CP0_Config1 & (1 << CP0C1_FP) != 0 {
+                let fr = env.CP0_Status & (1 << CP0St_FR) != 0;
+                let fpu = &mut env.active_fpu;
+
+                for (fpr, reg) in fpu.fpr.iter_mut().zip(regs.fpu.r.iter()) {
+                    if fr {
+                        fpr.d = *reg as u64;
+                    } else {
+                        unsafe { fpr.w[0] = *reg as u32 };
+                    }
+                }
+
+                // FIR is read-only
+                if regs.fpu.fcsr != old.fpu.fcsr {
+                    fpu.fcr31 = (regs.fpu.fcsr as u32 & fpu.fcr31_rw_bitmask)
+                        | (fpu.fcr31 & !fpu.fcr31_rw_bitmask);
+                    mips_restore_fp_status(fpu);
+                }
+            }
+        }
+
+        STATE.set_registers_written();
+
+        // Save the registers as written, read-only bits may not have changed
+        self.read_registers(&mut Default::default(), tid)
     }
 
+    // There are no register ids for PowerPC, gdb falls back to `g`/`G` without them
+    #[cfg(not(feature = "ppc"))]
+    fn support_single_register_access(
+        &mut self,
+    ) -> Option<ext::base::single_register_access::SingleRegisterAccessOps<'_, Tid, Self>> {
+        Some(self)
+    }
+
+    // Threads share an address space, so memory is always accessed through the current one.
+    // Breaks are scoped to the attached process, so that is always its address space.
     fn read_addrs(
         &mut self,
         addr: <Self::Arch as Arch>::Usize,
-        out: &mut [u8]
-    ) -> TargetResult<(), Self> {
+        out: &mut [u8],
+        _tid: Tid,
+    ) -> TargetResult<usize, Self> {
         let cpu = STATE.wait_for_cpu();
+        let addr = relocation::to_runtime(cpu, addr);
 
         if let Some(mem) = cpu.try_mem_read(addr, out.len()) {
             out.clone_from_slice(&mem);
-            Ok(())
+            Ok(out.len())
         } else {
             Err(TargetError::NonFatal)
         }
@@ -277,8 +707,10 @@ impl SingleThreadOps for PandaTarget {
         &mut self,
         addr: <Self::Arch as Arch>::Usize,
         data: &[u8],
+        _tid: Tid,
     ) -> TargetResult<(), Self> {
          let cpu = STATE.wait_for_cpu();
+         let addr = relocation::to_runtime(cpu, addr);
 
          cpu.mem_write(addr, data);
 
@@ -286,9 +718,144 @@ impl SingleThreadOps for PandaTarget {
     }
 }
 
-// Breakpoints: just say we implement software breakpoints
+// Single registers are read and written through the whole register file, so that the
+// same conversions and side effects apply
+#[cfg(not(feature = "ppc"))]
+impl ext::base::single_register_access::SingleRegisterAccess<Tid> for PandaTarget {
+    fn read_register(
+        &mut self,
+        tid: Tid,
+        reg_id: registers::RegId,
+        buf: &mut [u8],
+    ) -> TargetResult<usize, Self> {
+        let mut regs = Default::default();
+        self.read_registers(&mut regs, tid)?;
+
+        Ok(registers::register(&mut regs, reg_id).map_or(0, |reg| reg.read(buf)))
+    }
+
+    fn write_register(
+        &mut self,
+        tid: Tid,
+        reg_id: registers::RegId,
+        val: &[u8],
+    ) -> TargetResult<(), Self> {
+        let mut regs = Default::default();
+        self.read_registers(&mut regs, tid)?;
+
+        registers::register(&mut regs, reg_id)
+            .and_then(|mut reg| reg.write(val))
+            .ok_or(TargetError::NonFatal)?;
+
+        self.write_registers(&regs, tid)
+    }
+}
+
+/// Signals can't be delivered to the guest, and gdb passes the signal the CPU stopped
+/// with (e.g. `SIGINT` after Ctrl-C) back when resuming if told to, so drop them.
+/// Failing would end the gdb session.
+fn ignore_signal(signal: Option<Signal>) {
+    if let Some(signal) = signal {
+        eprintln!("[gdb] signals can't be delivered to the guest, ignoring {}", signal);
+    }
+}
+
+impl MultiThreadResume for PandaTarget {
+    fn resume(&mut self) -> Result<(), Self::Error> {
+        // gdb may have interrupted the CPU just as it stopped on its own
+        STATE.cancel_interrupt();
+
+        if !STATE.single_stepping() {
+            STATE.stop_instrumenting_all();
+        }
+
+        STATE.cont.signal(());
+
+        Ok(())
+    }
+
+    fn clear_resume_actions(&mut self) -> Result<(), Self::Error> {
+        STATE.stop_single_stepping();
+
+        Ok(())
+    }
+
+    fn set_resume_action_continue(&mut self, _tid: Tid, signal: Option<Signal>) -> Result<(), Self::Error> {
+        ignore_signal(signal);
+
+        Ok(())
+    }
+
+    fn support_single_step(&mut self) -> Option<ext::base::multithread::MultiThreadSingleStepOps<'_, Self>> {
+        Some(self)
+    }
+
+    // gdbstub can't fail a reverse step or continue without ending the session, so
+    // reverse execution is only offered when there are checkpoints to go back to. gdb
+    // then refuses the reverse commands itself.
+    fn support_reverse_step(&mut self) -> Option<ext::base::reverse_exec::ReverseStepOps<'_, Tid, Self>> {
+        if checkpoints_loaded() { Some(self) } else { None }
+    }
+
+    fn support_reverse_cont(&mut self) -> Option<ext::base::reverse_exec::ReverseContOps<'_, Tid, Self>> {
+        if checkpoints_loaded() { Some(self) } else { None }
+    }
+}
+
+/// Whether the `checkpoint` plugin is loaded. It takes the first checkpoint before the
+/// first block runs, so there is always one to restore.
+fn checkpoints_loaded() -> bool {
+    let name = CString::new("checkpoint").unwrap();
+
+    !unsafe { panda::sys::panda_get_plugin_by_name(name.as_ptr()) }.is_null()
+}
+
+/// Execute backwards by restoring the checkpoint before the current instruction and
+/// running forwards again
+fn reverse(step: bool) {
+    if STATE.start_reverse(step) {
+        STATE.cont.signal(());
+    } else {
+        // Not expected with the checkpoint plugin loaded. Nothing moved, but a stop
+        // has to be reported.
+        eprintln!("[gdb] no checkpoint to execute backwards from");
+        STATE.brk.signal(BreakStatus::ReplayStart);
+    }
+}
+
+impl ReverseStep<Tid> for PandaTarget {
+    fn reverse_step(&mut self, _tid: Tid) -> Result<(), Self::Error> {
+        reverse(true);
+
+        Ok(())
+    }
+}
+
+impl ReverseCont<Tid> for PandaTarget {
+    fn reverse_cont(&mut self) -> Result<(), Self::Error> {
+        reverse(false);
+
+        Ok(())
+    }
+}
+
+impl MultiThreadSingleStep for PandaTarget {
+    fn set_resume_action_step(&mut self, _tid: Tid, signal: Option<Signal>) -> Result<(), Self::Error> {
+        ignore_signal(signal);
+
+        STATE.start_single_stepping();
+
+        Ok(())
+    }
+}
+
+// Breakpoints: software breakpoints and memory-callback backed watchpoints
 impl ext::breakpoints::Breakpoints for PandaTarget {
-    fn sw_breakpoint(&mut self) -> Option<ext::breakpoints::SwBreakpointOps<'_, Self>> {
+    fn support_sw_breakpoint(&mut self) -> Option<ext::breakpoints::SwBreakpointOps<'_, Self>> {
+        Some(self as _)
+    }
+
+    fn support_hw_watchpoint(&mut self) -> Option<ext::breakpoints::HwWatchpointOps<'_, Self>> {
         Some(self as _)
     }
 }
@@ -300,7 +867,16 @@ impl ext::breakpoints::SwBreakpoint for PandaTarget {
         addr: <Self::Arch as Arch>::Usize,
         _kind: <Self::Arch as Arch>::BreakpointKind
     ) -> TargetResult<bool, Self> {
-        Ok(STATE.add_breakpoint(addr))
+        #[cfg(feature = "arm")]
+        let addr = arm::breakpoint_addr(addr, &_kind);
+        let runtime_addr = relocation::to_runtime(STATE.wait_for_cpu(), addr);
+
+        let added = STATE.add_breakpoint(runtime_addr);
+        if added {
+            relocation::BREAKPOINTS.insert(addr, runtime_addr);
+        }
+
+        Ok(added)
     }
 
     fn remove_sw_breakpoint(
@@ -308,10 +884,44 @@ impl ext::breakpoints::SwBreakpoint for PandaTarget {
         addr: <Self::Arch as Arch>::Usize,
         _kind: <Self::Arch as Arch>::BreakpointKind
     ) -> TargetResult<bool, Self> {
+        #[cfg(feature = "arm")]
+        let addr = arm::breakpoint_addr(addr, &_kind);
+        let addr = relocation::BREAKPOINTS.remove(STATE.wait_for_cpu(), addr);
+
         Ok(STATE.remove_breakpoint(addr))
     }
 }
 
+// Watchpoints, implemented using PANDA's virtual memory callbacks
+impl ext::breakpoints::HwWatchpoint for PandaTarget {
+    fn add_hw_watchpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        len: <Self::Arch as Arch>::Usize,
+        kind: ext::breakpoints::WatchKind,
+    ) -> TargetResult<bool, Self> {
+        let runtime_addr = relocation::to_runtime(STATE.wait_for_cpu(), addr);
+
+        let added = STATE.add_watchpoint(runtime_addr, len, kind);
+        if added {
+            relocation::WATCHPOINTS.insert(addr, runtime_addr);
+        }
+
+        Ok(added)
+    }
+
+    fn remove_hw_watchpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        len: <Self::Arch as Arch>::Usize,
+        kind: ext::breakpoints::WatchKind,
+    ) -> TargetResult<bool, Self> {
+        let addr = relocation::WATCHPOINTS.remove(STATE.wait_for_cpu(), addr);
+
+        Ok(STATE.remove_watchpoint(addr, len, kind))
+    }
+}
+
 impl ext::monitor_cmd::MonitorCmd for PandaTarget {
     fn handle_monitor_cmd(
         &mut self,
@@ -329,36 +939,230 @@ impl ext::monitor_cmd::MonitorCmd for PandaTarget {
     }
 }
 
+// Extended mode, used to attach to any process running in the guest. Processes can't be
+// started or killed by the debugger, so `run` and `kill` always fail.
+impl ext::extended_mode::ExtendedMode for PandaTarget {
+    fn run(
+        &mut self,
+        _filename: Option<&[u8]>,
+        _args: ext::extended_mode::Args<'_, '_>,
+    ) -> TargetResult<Pid, Self> {
+        Err(TargetError::NonFatal)
+    }
+
+    fn attach(&mut self, pid: Pid) -> TargetResult<(), Self> {
+        let cpu = STATE.wait_for_cpu();
+
+        let asid = OSI.get_processes(cpu)
+            .iter()
+            .find(|process| process.pid as usize == pid.get())
+            .map(|process| process.asid);
+
+        let asid = match asid {
+            Some(asid) => asid,
+            None => return Err(TargetError::NonFatal),
+        };
+
+        STATE.attach(pid.get() as _, asid as _);
+
+        // Run until the process is scheduled, so the registers and memory gdb reads
+        // once attached are those of the process
+        if !STATE.in_debugged_process(cpu) {
+            STATE.start_single_stepping();
+            STATE.cont.signal(());
+
+            if let BreakStatus::Exit = STATE.brk.wait_for() {
+                return Err(TargetError::Fatal(()));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn query_if_attached(&mut self, _pid: Pid) -> TargetResult<AttachKind, Self> {
+        // Detaching leaves processes running rather than killing them
+        Ok(AttachKind::Attach)
+    }
+
+    fn kill(&mut self, _pid: Option<Pid>) -> TargetResult<ShouldTerminate, Self> {
+        Err(TargetError::NonFatal)
+    }
+
+    fn restart(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn support_current_active_pid(&mut self) -> Option<ext::extended_mode::CurrentActivePidOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl CurrentActivePid for PandaTarget {
+    fn current_active_pid(&mut self) -> Result<Pid, Self::Error> {
+        // pids are mapped the same way as tids, as the idle task has pid 0
+        Ok(to_gdb_tid(STATE.debugged_pid()))
+    }
+}
+
+impl ext::catch_syscalls::CatchSyscalls for PandaTarget {
+    fn enable_catch_syscalls(
+        &mut self,
+        filter: Option<ext::catch_syscalls::SyscallNumbers<'_, <Self::Arch as Arch>::Usize>>,
+    ) -> TargetResult<(), Self> {
+        crate::syscalls::register_callbacks();
+        STATE.catch_syscalls(match filter {
+            Some(numbers) => CaughtSyscalls::Numbers(numbers.collect()),
+            None => CaughtSyscalls::All,
+        });
+
+        Ok(())
+    }
+
+    fn disable_catch_syscalls(&mut self) -> TargetResult<(), Self> {
+        STATE.catch_syscalls(CaughtSyscalls::None);
+
+        Ok(())
+    }
+}
+
 impl ext::section_offsets::SectionOffsets for PandaTarget {
     fn get_section_offsets(&mut self) -> Result<ext::section_offsets::Offsets<<Self::Arch as Arch>::Usize>, Self::Error> {
         let cpu = STATE.wait_for_cpu();
-        let mut process = OSI.get_current_process(cpu);
-        let mappings = OSI.get_mappings(cpu, &mut *process);
-        if mappings.len() >= 3 {
-            let text = &mappings[0];
-            let data = &mappings[1];
-            let bss = &mappings[2];
-            Ok(ext::section_offsets::Offsets::Sections {
-                text: text.base,
-                data: data.base,
-                bss: Some(bss.base),
-            })
+
+        // With a base address gdb already sees the executable where the disassembler has it,
+        // see `relocation`. Otherwise failing here ends the session, so fall back to not
+        // relocating anything, `monitor procinfo` shows why.
+        let (text, data) = if relocation::enabled() {
+            (0, 0)
         } else {
-            Err(())
+            libraries::exec_section_offsets(cpu).unwrap_or_else(|err| {
+                eprintln!("[gdb] can't find the section offsets of the executable, {}", err);
+                (0, 0)
+            })
+        };
+
+        Ok(ext::section_offsets::Offsets::Sections {
+            text,
+            data,
+            bss: Some(data),
+        })
+    }
+}
+
+impl ext::exec_file::ExecFile for PandaTarget {
+    fn get_exec_file(
+        &self,
+        pid: Option<Pid>,
+        offset: u64,
+        length: usize,
+        buf: &mut [u8],
+    ) -> TargetResult<usize, Self> {
+        let cpu = STATE.wait_for_cpu();
+
+        match libraries::exec_file(cpu, pid.map(from_gdb_tid)) {
+            Some(path) => Ok(copy_xfer_data(path.as_bytes(), offset, length, buf)),
+            None => Err(TargetError::NonFatal),
         }
     }
 }
 
-#[cfg(any(feature = "x86_64", feature = "i386"))]
-fn fpreg_to_bytes(x: &panda::sys::FPReg) -> F80 {
-    unsafe {
-        std::mem::transmute_copy(x)
+impl ext::libraries::LibrariesSvr4 for PandaTarget {
+    fn get_libraries_svr4(
+        &self,
+        offset: u64,
+        length: usize,
+        buf: &mut [u8],
+    ) -> TargetResult<usize, Self> {
+        let cpu = STATE.wait_for_cpu();
+        let xml = libraries::libraries_svr4_xml(cpu);
+
+        Ok(copy_xfer_data(xml.as_bytes(), offset, length, buf))
     }
 }
 
-#[cfg(any(feature = "x86_64", feature = "i386"))]
-fn zmm_to_xmm(x: &panda::sys::ZMMReg) -> u128 {
-    unsafe {
-        std::mem::transmute_copy(x)
+// Generated when gdb asks for it, which is once per connection
+impl ext::memory_map::MemoryMap for PandaTarget {
+    fn memory_map_xml(
+        &self,
+        offset: u64,
+        length: usize,
+        buf: &mut [u8],
+    ) -> TargetResult<usize, Self> {
+        let cpu = STATE.wait_for_cpu();
+        let xml = memory_map::memory_map_xml(cpu);
+
+        Ok(copy_xfer_data(xml.as_bytes(), offset, length, buf))
     }
 }
+
+// Only known for processes which were seen starting, see `proc_start`
+impl ext::auxv::Auxv for PandaTarget {
+    fn get_auxv(&self, offset: u64, length: usize, buf: &mut [u8]) -> TargetResult<usize, Self> {
+        let cpu = STATE.wait_for_cpu();
+
+        match proc_start::current(cpu) {
+            Some(start) if !start.auxv.is_empty() => {
+                Ok(copy_xfer_data(&start.auxv, offset, length, buf))
+            }
+            _ => Err(TargetError::NonFatal),
+        }
+    }
+}
+
+/// Copy the part of `data` a `qXfer` read asks for into `buf`, returning the length copied
+fn copy_xfer_data(data: &[u8], offset: u64, length: usize, buf: &mut [u8]) -> usize {
+    let data = data.get(offset as usize..).unwrap_or(&[]);
+    let len = data.len().min(length).min(buf.len());
+
+    buf[..len].copy_from_slice(&data[..len]);
+    len
+}
+
+// The parts of the FPSCR which make up the AArch64 FPSR and FPCR
+#[cfg(feature = "aarch64")]
+const FPSR_MASK: u32 = 0xf800009f;
+#[cfg(feature = "aarch64")]
+const FPCR_MASK: u32 = 0x07f79f00;
+
+// PSTATE bits QEMU keeps outside of `pstate`
+#[cfg(feature = "aarch64")]
+const PSTATE_Z: u32 = 1 << 30;
+#[cfg(feature = "aarch64")]
+const PSTATE_DAIF: u32 = 0xf << 6;
+#[cfg(feature = "aarch64")]
+const PSTATE_NZCV: u32 = 0xf << 28;
+
+/// Equivalent of QEMU's `pstate_read`, as the condition flags are stored separately
+#[cfg(feature = "aarch64")]
+fn aarch64_pstate_read(env: &panda::sys::CPUARMState) -> u32 {
+    (env.NF & 0x80000000)
+        | ((env.ZF == 0) as u32) << 30
+        | env.CF << 29
+        | (env.VF & 0x80000000) >> 3
+        | env.pstate
+        | env.daif as u32
+}
+
+/// Equivalent of QEMU's `pstate_write`
+#[cfg(feature = "aarch64")]
+fn aarch64_pstate_write(env: &mut panda::sys::CPUARMState, val: u32) {
+    env.ZF = !val & PSTATE_Z;
+    env.NF = val;
+    env.CF = (val >> 29) & 1;
+    env.VF = (val << 3) & 0x80000000;
+    env.daif = (val & PSTATE_DAIF) as _;
+    env.pstate = val & !(PSTATE_NZCV | PSTATE_DAIF);
+}
+
+/// Equivalent of QEMU's `restore_fp_status`, which is inline so can't be called
+#[cfg(any(feature = "mips", feature = "mipsel", feature = "mips64"))]
+fn mips_restore_fp_status(fpu: &mut panda::sys::CPUMIPSFPUContext) {
+    use panda::sys::{FCR31_FS, FCR31_NAN2008};
+
+    // MIPS rounding modes (nearest, zero, +inf, -inf) in softfloat's numbering
+    const IEEE_RM: [i8; 4] = [0, 3, 2, 1];
+
+    fpu.fp_status.float_rounding_mode = IEEE_RM[(fpu.fcr31 & 3) as usize];
+    fpu.fp_status.flush_to_zero = (fpu.fcr31 & (1 << FCR31_FS) != 0) as u8;
+    fpu.fp_status.snan_bit_is_one = (fpu.fcr31 & (1 << FCR31_NAN2008) == 0) as u8;
+}
@@ -0,0 +1,172 @@
+//! What processes were started with, as recorded by `proc_start_linux` when they start:
+//! the auxiliary vector, arguments and environment, which OSI doesn't provide.
+
+use panda::prelude::*;
+use panda::plugins::osi::OSI;
+use panda::sys::target_pid_t;
+
+use crate::breakpoint_condition::read_ptr;
+use crate::libraries;
+
+use std::collections::{HashMap, HashSet};
+use std::os::raw::c_char;
+use std::sync::RwLock;
+
+const AT_NULL: target_ptr_t = 0;
+
+/// Upper bound on the entries read from the auxiliary vector, it has around 20
+const MAX_AUXV_ENTRIES: usize = 64;
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ProcStart {
+    pub argv: Vec<String>,
+    pub envp: Vec<String>,
+    /// The auxiliary vector as it is in guest memory, ending with `AT_NULL`. Empty if
+    /// it couldn't be read.
+    pub auxv: Vec<u8>,
+    /// `AT_ENTRY`, the entry point of the executable
+    pub entry: target_ulong,
+    /// `AT_BASE`, the base address of the dynamic loader
+    pub base: target_ulong,
+    /// The task of the process's thread group leader, which tells it apart from a later
+    /// process reusing its PID
+    taskd: target_ptr_t,
+    /// Path of the executable started
+    exec: Option<String>,
+}
+
+lazy_static::lazy_static! {
+    static ref PROC_STARTS: RwLock<HashMap<target_pid_t, ProcStart>> = RwLock::new(HashMap::new());
+}
+
+impl ProcStart {
+    /// The `PWD` environment variable the process was started with, which is normally the
+    /// working directory it was started in. The kernel's copy of the working directory
+    /// isn't available.
+    pub fn pwd(&self) -> Option<&str> {
+        self.envp.iter().find_map(|var| var.strip_prefix("PWD="))
+    }
+}
+
+/// Record the process starting on the CPU, replacing what it was started with before if it
+/// calls `exec` again. The arguments, environment and auxiliary vector are read from the
+/// stack in full, `proc_start_linux` only copies the first few.
+#[cfg(not(any(feature = "ppc", feature = "mips64")))]
+pub(crate) fn record(cpu: &mut CPUState, vals: &panda::plugins::proc_start_linux::AuxvValues) {
+    let proc = OSI.get_current_process(cpu);
+
+    let (argv, envp, auxv) = read_stack(cpu, vals.argv_ptr_ptr as target_ptr_t).unwrap_or_else(|| {
+        let argv = vals.argv[..vals.argc as usize].iter().map(|arg| c_string(arg)).collect();
+        let envp = vals.envp[..vals.envc as usize].iter().map(|var| c_string(var)).collect();
+
+        (argv, envp, Vec::new())
+    });
+
+    let start = ProcStart {
+        argv,
+        envp,
+        auxv,
+        entry: vals.entry,
+        base: vals.base,
+        taskd: proc.taskd,
+        exec: libraries::exec_file(cpu, None),
+    };
+
+    // Processes which exited since the last one started are forgotten
+    let running: HashSet<_> = OSI.get_processes(cpu).iter().map(|proc| (proc.pid, proc.taskd)).collect();
+    let mut starts = PROC_STARTS.write().unwrap();
+
+    starts.retain(|&pid, start| running.contains(&(pid, start.taskd)));
+    starts.insert(proc.pid, start);
+}
+
+/// What the current process was started with. A process which forked without calling
+/// `exec` shares its parent's, as long as it is still running the same executable.
+pub(crate) fn current(cpu: &mut CPUState) -> Option<ProcStart> {
+    let proc = OSI.get_current_process(cpu);
+    let starts = PROC_STARTS.read().unwrap();
+
+    if let Some(start) = starts.get(&proc.pid).filter(|start| start.taskd == proc.taskd) {
+        return Some(start.clone());
+    }
+
+    let parent = starts.get(&proc.ppid).filter(|parent| parent.exec.is_some())?;
+    if parent.exec == libraries::exec_file(cpu, None) {
+        Some(parent.clone())
+    } else {
+        None
+    }
+}
+
+/// Read the argument and environment strings and the auxiliary vector from the new
+/// process's stack, which are laid out one after another starting with argv
+fn read_stack(cpu: &mut CPUState, argv_ptr: target_ptr_t) -> Option<(Vec<String>, Vec<String>, Vec<u8>)> {
+    const PTR_SIZE: target_ptr_t = std::mem::size_of::<target_ptr_t>() as target_ptr_t;
+
+    let mut ptr = argv_ptr;
+    let mut read_strings = |cpu: &mut CPUState| {
+        let mut strings = Vec::new();
+        loop {
+            let string = read_ptr(cpu, ptr)?;
+            ptr += PTR_SIZE;
+
+            if string == 0 {
+                return Some(strings);
+            }
+
+            strings.push(read_string(cpu, string)?);
+        }
+    };
+
+    let argv = read_strings(cpu)?;
+    let envp = read_strings(cpu)?;
+
+    let auxv_ptr = ptr;
+    let mut len = 0;
+    for _ in 0..MAX_AUXV_ENTRIES {
+        let entry = read_ptr(cpu, ptr)?;
+        ptr += PTR_SIZE * 2;
+        len += PTR_SIZE * 2;
+
+        if entry == AT_NULL {
+            break;
+        }
+    }
+
+    let auxv = cpu.try_mem_read(auxv_ptr, len as usize)?;
+
+    Some((argv, envp, auxv))
+}
+
+/// Read a nul-terminated string from guest memory, a page at a time so reading doesn't
+/// fail from running into an unmapped page. Linux limits each string to 32 pages.
+pub(crate) fn read_string(cpu: &mut CPUState, mut addr: target_ptr_t) -> Option<String> {
+    const PAGE_SIZE: target_ptr_t = 0x1000;
+    const MAX_PAGES: usize = 32;
+
+    let mut bytes = Vec::new();
+    for _ in 0..=MAX_PAGES {
+        let len = PAGE_SIZE - (addr % PAGE_SIZE);
+        let chunk = cpu.try_mem_read(addr, len as usize)?;
+
+        match chunk.iter().position(|&byte| byte == 0) {
+            Some(end) => {
+                bytes.extend_from_slice(&chunk[..end]);
+                return Some(String::from_utf8_lossy(&bytes).into_owned());
+            }
+            None => bytes.extend_from_slice(&chunk),
+        }
+
+        addr += len;
+    }
+
+    None
+}
+
+/// Convert one of the strings `proc_start_linux` copies out of guest memory. They're copied
+/// with `strncpy`, so a string filling its buffer isn't nul-terminated.
+fn c_string(chars: &[c_char]) -> String {
+    let bytes: Vec<u8> = chars.iter().map(|&c| c as u8).take_while(|&byte| byte != 0).collect();
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
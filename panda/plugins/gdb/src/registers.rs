@@ -0,0 +1,610 @@
+//! Conversions between the register layouts gdb expects and the way QEMU stores them,
+//! following what QEMU's own gdbstub does for each architecture.
+
+use crate::panda_target::PandaTarget;
+use gdbstub::arch::Arch;
+use gdbstub::target::Target;
+
+#[cfg(any(feature = "x86_64", feature = "i386"))]
+use gdbstub_arch::x86::reg::{F80, X86SegmentRegs, X87FpuInternalRegs};
+
+#[cfg(any(feature = "x86_64", feature = "i386"))]
+use panda::sys::CPUX86State;
+
+pub type Registers = <<PandaTarget as Target>::Arch as Arch>::Registers;
+pub type RegId = <<PandaTarget as Target>::Arch as Arch>::RegId;
+
+/// A single register within the register file, for the `p` and `P` packets
+pub enum RegValue<'a> {
+    U32(&'a mut u32),
+    U64(&'a mut u64),
+    U128(&'a mut u128),
+    Bytes(&'a mut [u8]),
+    /// A register stored as a u64 which gdb sees as being the given number of bytes
+    Truncated(&'a mut u64, usize),
+    /// Consecutive 128-bit elements
+    Vector(&'a mut [u128]),
+}
+
+macro_rules! to_target_bytes {
+    ($val:expr) => {{
+        #[cfg(any(feature = "ppc", feature = "mips", feature = "mips64"))]
+        let bytes = $val.to_be_bytes();
+
+        #[cfg(not(any(feature = "ppc", feature = "mips", feature = "mips64")))]
+        let bytes = $val.to_le_bytes();
+
+        bytes
+    }};
+}
+
+macro_rules! from_target_bytes {
+    ($ty:ty, $bytes:expr) => {{
+        let bytes = $bytes.try_into().ok()?;
+
+        #[cfg(any(feature = "ppc", feature = "mips", feature = "mips64"))]
+        let val = <$ty>::from_be_bytes(bytes);
+
+        #[cfg(not(any(feature = "ppc", feature = "mips", feature = "mips64")))]
+        let val = <$ty>::from_le_bytes(bytes);
+
+        val
+    }};
+}
+
+impl RegValue<'_> {
+    /// Write the value into `buf` in target byte order, returning the size
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        let vector: Vec<u8>;
+        let bytes: &[u8] = match self {
+            RegValue::U32(val) => &to_target_bytes!(**val)[..],
+            RegValue::U64(val) => &to_target_bytes!(**val)[..],
+            RegValue::U128(val) => &to_target_bytes!(**val)[..],
+            RegValue::Bytes(val) => val,
+            RegValue::Truncated(val, size) => &to_target_bytes!(**val)[..*size],
+            RegValue::Vector(val) => {
+                vector = val.iter().flat_map(|val| to_target_bytes!(val)).collect();
+                &vector
+            }
+        };
+
+        buf[..bytes.len()].copy_from_slice(bytes);
+        bytes.len()
+    }
+
+    /// Set the value from `val` in target byte order, returns None if the size is wrong
+    pub fn write(&mut self, val: &[u8]) -> Option<()> {
+        use std::convert::TryInto;
+
+        match self {
+            RegValue::U32(reg) => **reg = from_target_bytes!(u32, val),
+            RegValue::U64(reg) => **reg = from_target_bytes!(u64, val),
+            RegValue::U128(reg) => **reg = from_target_bytes!(u128, val),
+            RegValue::Bytes(reg) if reg.len() == val.len() => reg.copy_from_slice(val),
+            RegValue::Bytes(_) => return None,
+            RegValue::Truncated(reg, size) if *size == val.len() => {
+                let mut bytes = to_target_bytes!(0u64);
+                bytes[..*size].copy_from_slice(val);
+                **reg = from_target_bytes!(u64, &bytes[..]);
+            }
+            RegValue::Truncated(..) => return None,
+            RegValue::Vector(reg) if reg.len() * 16 == val.len() => {
+                for (reg, val) in reg.iter_mut().zip(val.chunks(16)) {
+                    *reg = from_target_bytes!(u128, val);
+                }
+            }
+            RegValue::Vector(_) => return None,
+        }
+
+        Some(())
+    }
+}
+
+/// Find the register `id` refers to within the register file. Returns None for
+/// registers which aren't part of it.
+#[cfg(feature = "x86_64")]
+pub fn register(regs: &mut Registers, id: RegId) -> Option<RegValue<'_>> {
+    use crate::arch::x86::{self, VectorValue, X86RegId};
+    use gdbstub_arch::x86::reg::id::X86_64CoreRegId;
+
+    let (regs, id) = match id {
+        X86RegId::Core(id) => (&mut regs.core, id),
+        X86RegId::System(i) => {
+            return Some(RegValue::Truncated(regs.system.get_mut(i)?, x86::system_reg_size(i)));
+        }
+        X86RegId::Vector(i) => {
+            return Some(match x86::vector_reg(i)? {
+                VectorValue::Lanes { zmm, start, end } => RegValue::Vector(&mut regs.zmm[zmm][start..end]),
+                VectorValue::Opmask(i) => RegValue::U64(&mut regs.k[i]),
+            });
+        }
+    };
+
+    Some(match id {
+        X86_64CoreRegId::Gpr(i) => RegValue::U64(regs.regs.get_mut(i as usize)?),
+        X86_64CoreRegId::Rip => RegValue::U64(&mut regs.rip),
+        X86_64CoreRegId::Eflags => RegValue::U32(&mut regs.eflags),
+        X86_64CoreRegId::Segment(seg) => RegValue::U32(segment(&mut regs.segments, seg)),
+        X86_64CoreRegId::St(i) => RegValue::Bytes(regs.st.get_mut(i as usize)?),
+        X86_64CoreRegId::Fpu(reg) => RegValue::U32(fpu_register(&mut regs.fpu, reg)),
+        X86_64CoreRegId::Xmm(i) => RegValue::U128(regs.xmm.get_mut(i as usize)?),
+        X86_64CoreRegId::Mxcsr => RegValue::U32(&mut regs.mxcsr),
+        _ => return None,
+    })
+}
+
+#[cfg(feature = "i386")]
+pub fn register(regs: &mut Registers, id: RegId) -> Option<RegValue<'_>> {
+    use crate::arch::x86::{self, VectorValue, X86RegId};
+    use gdbstub_arch::x86::reg::id::X86CoreRegId;
+
+    let (regs, id) = match id {
+        X86RegId::Core(id) => (&mut regs.core, id),
+        X86RegId::System(i) => {
+            return Some(RegValue::Truncated(regs.system.get_mut(i)?, x86::system_reg_size(i)));
+        }
+        X86RegId::Vector(i) => {
+            return Some(match x86::vector_reg(i)? {
+                VectorValue::Lanes { zmm, start, end } => RegValue::Vector(&mut regs.zmm[zmm][start..end]),
+                VectorValue::Opmask(i) => RegValue::U64(&mut regs.k[i]),
+            });
+        }
+    };
+
+    Some(match id {
+        X86CoreRegId::Eax => RegValue::U32(&mut regs.eax),
+        X86CoreRegId::Ecx => RegValue::U32(&mut regs.ecx),
+        X86CoreRegId::Edx => RegValue::U32(&mut regs.edx),
+        X86CoreRegId::Ebx => RegValue::U32(&mut regs.ebx),
+        X86CoreRegId::Esp => RegValue::U32(&mut regs.esp),
+        X86CoreRegId::Ebp => RegValue::U32(&mut regs.ebp),
+        X86CoreRegId::Esi => RegValue::U32(&mut regs.esi),
+        X86CoreRegId::Edi => RegValue::U32(&mut regs.edi),
+        X86CoreRegId::Eip => RegValue::U32(&mut regs.eip),
+        X86CoreRegId::Eflags => RegValue::U32(&mut regs.eflags),
+        X86CoreRegId::Segment(seg) => RegValue::U32(segment(&mut regs.segments, seg)),
+        X86CoreRegId::St(i) => RegValue::Bytes(regs.st.get_mut(i as usize)?),
+        X86CoreRegId::Fpu(reg) => RegValue::U32(fpu_register(&mut regs.fpu, reg)),
+        X86CoreRegId::Xmm(i) => RegValue::U128(regs.xmm.get_mut(i as usize)?),
+        X86CoreRegId::Mxcsr => RegValue::U32(&mut regs.mxcsr),
+        _ => return None,
+    })
+}
+
+#[cfg(feature = "arm")]
+pub fn register(regs: &mut Registers, id: RegId) -> Option<RegValue<'_>> {
+    use crate::arch::arm::ArmRegId;
+    use gdbstub_arch::arm::reg::id::ArmCoreRegId;
+
+    let (regs, id) = match id {
+        ArmRegId::Core(id) => (&mut regs.core, id),
+        ArmRegId::D(i) => return Some(RegValue::U64(regs.d.get_mut(i)?)),
+        ArmRegId::Fpscr => return Some(RegValue::U32(&mut regs.fpscr)),
+        ArmRegId::Banked(i) => return Some(RegValue::U32(regs.banked.get_mut(i)?)),
+    };
+
+    Some(match id {
+        ArmCoreRegId::Gpr(i) => RegValue::U32(regs.r.get_mut(i as usize)?),
+        ArmCoreRegId::Sp => RegValue::U32(&mut regs.sp),
+        ArmCoreRegId::Lr => RegValue::U32(&mut regs.lr),
+        ArmCoreRegId::Pc => RegValue::U32(&mut regs.pc),
+        ArmCoreRegId::Cpsr => RegValue::U32(&mut regs.cpsr),
+        _ => return None,
+    })
+}
+
+#[cfg(feature = "aarch64")]
+pub fn register(regs: &mut Registers, id: RegId) -> Option<RegValue<'_>> {
+    use gdbstub_arch::aarch64::reg::id::AArch64RegId;
+
+    Some(match id {
+        AArch64RegId::X(i) => RegValue::U64(regs.x.get_mut(i as usize)?),
+        AArch64RegId::Sp => RegValue::U64(&mut regs.sp),
+        AArch64RegId::Pc => RegValue::U64(&mut regs.pc),
+        AArch64RegId::Pstate => RegValue::U32(&mut regs.cpsr),
+        AArch64RegId::V(i) => RegValue::U128(regs.v.get_mut(i as usize)?),
+        AArch64RegId::Fpcr => RegValue::U32(&mut regs.fpcr),
+        AArch64RegId::Fpsr => RegValue::U32(&mut regs.fpsr),
+        _ => return None,
+    })
+}
+
+#[cfg(any(feature = "mips", feature = "mipsel"))]
+pub fn register(regs: &mut Registers, id: RegId) -> Option<RegValue<'_>> {
+    use gdbstub_arch::mips::reg::id::MipsRegId;
+
+    Some(match id {
+        MipsRegId::Gpr(i) => RegValue::U32(regs.r.get_mut(i as usize)?),
+        MipsRegId::Status => RegValue::U32(&mut regs.cp0.status),
+        MipsRegId::Lo => RegValue::U32(&mut regs.lo),
+        MipsRegId::Hi => RegValue::U32(&mut regs.hi),
+        MipsRegId::Badvaddr => RegValue::U32(&mut regs.cp0.badvaddr),
+        MipsRegId::Cause => RegValue::U32(&mut regs.cp0.cause),
+        MipsRegId::Pc => RegValue::U32(&mut regs.pc),
+        MipsRegId::Fpr(i) => RegValue::U32(regs.fpu.r.get_mut(i as usize)?),
+        MipsRegId::Fcsr => RegValue::U32(&mut regs.fpu.fcsr),
+        MipsRegId::Fir => RegValue::U32(&mut regs.fpu.fir),
+        _ => return None,
+    })
+}
+
+#[cfg(feature = "mips64")]
+pub fn register(regs: &mut Registers, id: RegId) -> Option<RegValue<'_>> {
+    use gdbstub_arch::mips::reg::id::MipsRegId;
+
+    Some(match id {
+        MipsRegId::Gpr(i) => RegValue::U64(regs.r.get_mut(i as usize)?),
+        MipsRegId::Status => RegValue::U64(&mut regs.cp0.status),
+        MipsRegId::Lo => RegValue::U64(&mut regs.lo),
+        MipsRegId::Hi => RegValue::U64(&mut regs.hi),
+        MipsRegId::Badvaddr => RegValue::U64(&mut regs.cp0.badvaddr),
+        MipsRegId::Cause => RegValue::U64(&mut regs.cp0.cause),
+        MipsRegId::Pc => RegValue::U64(&mut regs.pc),
+        MipsRegId::Fpr(i) => RegValue::U64(regs.fpu.r.get_mut(i as usize)?),
+        MipsRegId::Fcsr => RegValue::U64(&mut regs.fpu.fcsr),
+        MipsRegId::Fir => RegValue::U64(&mut regs.fpu.fir),
+        _ => return None,
+    })
+}
+
+#[cfg(any(feature = "x86_64", feature = "i386"))]
+fn segment(segments: &mut X86SegmentRegs, id: gdbstub_arch::x86::reg::id::X86SegmentRegId) -> &mut u32 {
+    use gdbstub_arch::x86::reg::id::X86SegmentRegId;
+
+    match id {
+        X86SegmentRegId::CS => &mut segments.cs,
+        X86SegmentRegId::SS => &mut segments.ss,
+        X86SegmentRegId::DS => &mut segments.ds,
+        X86SegmentRegId::ES => &mut segments.es,
+        X86SegmentRegId::FS => &mut segments.fs,
+        X86SegmentRegId::GS => &mut segments.gs,
+    }
+}
+
+#[cfg(any(feature = "x86_64", feature = "i386"))]
+fn fpu_register(fpu: &mut X87FpuInternalRegs, id: gdbstub_arch::x86::reg::id::X87FpuInternalRegId) -> &mut u32 {
+    use gdbstub_arch::x86::reg::id::X87FpuInternalRegId;
+
+    match id {
+        X87FpuInternalRegId::Fctrl => &mut fpu.fctrl,
+        X87FpuInternalRegId::Fstat => &mut fpu.fstat,
+        X87FpuInternalRegId::Ftag => &mut fpu.ftag,
+        X87FpuInternalRegId::Fiseg => &mut fpu.fiseg,
+        X87FpuInternalRegId::Fioff => &mut fpu.fioff,
+        X87FpuInternalRegId::Foseg => &mut fpu.foseg,
+        X87FpuInternalRegId::Fooff => &mut fpu.fooff,
+        X87FpuInternalRegId::Fop => &mut fpu.fop,
+    }
+}
+
+// Condition code bits of EFLAGS, which QEMU computes lazily
+#[cfg(any(feature = "x86_64", feature = "i386"))]
+const CC_MASK: u32 = 0x8d5;
+#[cfg(any(feature = "x86_64", feature = "i386"))]
+const DF_MASK: u32 = 1 << 10;
+#[cfg(any(feature = "x86_64", feature = "i386"))]
+const VM_MASK: u32 = 1 << 17;
+#[cfg(any(feature = "x86_64", feature = "i386"))]
+const CC_OP_EFLAGS: u32 = 1;
+
+/// The guest-visible EFLAGS, equivalent of QEMU's `cpu_compute_eflags`
+#[cfg(any(feature = "x86_64", feature = "i386"))]
+pub fn x86_eflags(env: &mut CPUX86State) -> u32 {
+    let cc = unsafe { panda::sys::cpu_cc_compute_all(env, env.cc_op as _) };
+
+    env.eflags as u32 | cc | (env.df as u32 & DF_MASK)
+}
+
+/// Equivalent of QEMU's `cpu_load_eflags`, splitting the flags QEMU keeps separately
+/// back out of EFLAGS
+#[cfg(any(feature = "x86_64", feature = "i386"))]
+pub fn x86_load_eflags(env: &mut CPUX86State, eflags: u32) {
+    env.cc_src = (eflags & CC_MASK) as _;
+    env.cc_op = CC_OP_EFLAGS;
+    env.df = 1 - 2 * ((eflags >> 10) & 1) as i32;
+    env.eflags = ((eflags & !(CC_MASK | DF_MASK)) | 0x2) as _;
+}
+
+/// The segment selectors, in gdb's order
+#[cfg(any(feature = "x86_64", feature = "i386"))]
+pub fn x86_segments(env: &CPUX86State) -> X86SegmentRegs {
+    use panda::sys::{R_CS, R_SS, R_DS, R_ES, R_FS, R_GS};
+
+    X86SegmentRegs {
+        cs: env.segs[R_CS as usize].selector,
+        ss: env.segs[R_SS as usize].selector,
+        ds: env.segs[R_DS as usize].selector,
+        es: env.segs[R_ES as usize].selector,
+        fs: env.segs[R_FS as usize].selector,
+        gs: env.segs[R_GS as usize].selector,
+    }
+}
+
+/// Load any segment registers which changed, looking up their descriptors
+#[cfg(any(feature = "x86_64", feature = "i386"))]
+pub fn x86_load_segments(env: &mut CPUX86State, segments: &X86SegmentRegs) {
+    use panda::sys::{R_CS, R_SS, R_DS, R_ES, R_FS, R_GS};
+
+    for &(seg, selector) in &[
+        (R_CS, segments.cs),
+        (R_SS, segments.ss),
+        (R_DS, segments.ds),
+        (R_ES, segments.es),
+        (R_FS, segments.fs),
+        (R_GS, segments.gs),
+    ] {
+        x86_load_segment(env, seg as usize, selector & 0xffff);
+    }
+}
+
+// Descriptor and hidden flag bits used when loading a segment
+#[cfg(any(feature = "x86_64", feature = "i386"))]
+mod desc {
+    pub const B_MASK: u32 = 1 << 22;
+    pub const L_MASK: u32 = 1 << 21;
+    pub const P_MASK: u32 = 1 << 15;
+    pub const DPL_SHIFT: u32 = 13;
+    pub const S_MASK: u32 = 1 << 12;
+    pub const W_MASK: u32 = 1 << 9;
+    pub const A_MASK: u32 = 1 << 8;
+
+    pub const HF_CPL_MASK: u32 = 3;
+    pub const HF_CS32_MASK: u32 = 1 << 4;
+    pub const HF_SS32_MASK: u32 = 1 << 5;
+    pub const HF_ADDSEG_MASK: u32 = 1 << 6;
+    pub const HF_LMA_MASK: u32 = 1 << 14;
+    pub const HF_CS64_MASK: u32 = 1 << 15;
+
+    pub const CR0_PE_MASK: u32 = 1;
+}
+
+/// Equivalent of QEMU's `x86_cpu_gdb_load_seg`, the descriptor is looked up in the
+/// guest's descriptor tables and selectors without a valid descriptor are ignored
+#[cfg(any(feature = "x86_64", feature = "i386"))]
+fn x86_load_segment(env: &mut CPUX86State, seg: usize, selector: u32) {
+    use desc::*;
+
+    if env.segs[seg].selector == selector {
+        return;
+    }
+
+    let protected = env.cr[0] as u32 & CR0_PE_MASK != 0;
+    let vm86 = env.eflags as u32 & VM_MASK != 0;
+
+    let (base, limit, flags) = if !protected || vm86 {
+        let dpl = if vm86 { 3 } else { 0 };
+        let flags = P_MASK | S_MASK | W_MASK | A_MASK | (dpl << DPL_SHIFT);
+
+        ((selector << 4) as _, 0xffff, flags)
+    } else {
+        let (mut base, mut limit, mut flags) = (0, 0, 0);
+        let found = unsafe {
+            panda::sys::cpu_x86_get_descr_debug(env, selector, &mut base, &mut limit, &mut flags)
+        };
+
+        if found == 0 {
+            return;
+        }
+
+        (base, limit, flags)
+    };
+
+    x86_load_seg_cache(env, seg, selector, base, limit, flags);
+}
+
+/// Equivalent of QEMU's `cpu_x86_load_seg_cache`, which also updates the hidden flags
+/// derived from the segment registers
+#[cfg(any(feature = "x86_64", feature = "i386"))]
+fn x86_load_seg_cache(
+    env: &mut CPUX86State,
+    seg: usize,
+    selector: u32,
+    base: panda::prelude::target_ulong,
+    limit: u32,
+    flags: u32,
+) {
+    use desc::*;
+    use panda::sys::{R_CS, R_SS, R_DS, R_ES};
+
+    let sc = &mut env.segs[seg];
+    sc.selector = selector;
+    sc.base = base;
+    sc.limit = limit;
+    sc.flags = flags;
+
+    if seg == R_CS as usize {
+        if cfg!(feature = "x86_64") && env.hflags & HF_LMA_MASK != 0 && flags & L_MASK != 0 {
+            env.hflags |= HF_CS32_MASK | HF_SS32_MASK | HF_CS64_MASK;
+            env.hflags &= !HF_ADDSEG_MASK;
+        } else {
+            let cs32 = (flags & B_MASK) >> (22 - 4);
+            env.hflags = (env.hflags & !(HF_CS32_MASK | HF_CS64_MASK)) | cs32;
+        }
+    }
+
+    if seg == R_SS as usize {
+        let cpl = (flags >> DPL_SHIFT) & 3;
+        env.hflags = (env.hflags & !HF_CPL_MASK) | cpl;
+    }
+
+    let mut new_hflags = (env.segs[R_SS as usize].flags & B_MASK) >> (22 - 5);
+    if env.hflags & HF_CS64_MASK != 0 {
+        // Zero base assumed for DS, ES and SS in long mode
+    } else if env.cr[0] as u32 & CR0_PE_MASK == 0
+        || env.eflags as u32 & VM_MASK != 0
+        || env.hflags & HF_CS32_MASK == 0
+    {
+        new_hflags |= HF_ADDSEG_MASK;
+    } else {
+        let bases = env.segs[R_DS as usize].base | env.segs[R_ES as usize].base | env.segs[R_SS as usize].base;
+        if bases != 0 {
+            new_hflags |= HF_ADDSEG_MASK;
+        }
+    }
+
+    env.hflags = (env.hflags & !(HF_SS32_MASK | HF_ADDSEG_MASK)) | new_hflags;
+}
+
+/// The x87 stack, `st[0]` being the top of the stack
+#[cfg(any(feature = "x86_64", feature = "i386"))]
+pub fn x86_st(env: &CPUX86State) -> [F80; 8] {
+    let mut st = [[0; 10]; 8];
+    for (i, st) in st.iter_mut().enumerate() {
+        let reg = unsafe { env.fpregs[(env.fpstt as usize + i) & 7].d };
+
+        st[..8].copy_from_slice(&reg.low.to_le_bytes());
+        st[8..].copy_from_slice(&reg.high.to_le_bytes());
+    }
+
+    st
+}
+
+#[cfg(any(feature = "x86_64", feature = "i386"))]
+pub fn x86_load_st(env: &mut CPUX86State, st: &[F80; 8]) {
+    use std::convert::TryInto;
+
+    for (i, st) in st.iter().enumerate() {
+        env.fpregs[(env.fpstt as usize + i) & 7].d = panda::sys::floatx80 {
+            low: u64::from_le_bytes(st[..8].try_into().unwrap()),
+            high: u16::from_le_bytes(st[8..].try_into().unwrap()),
+        };
+    }
+}
+
+/// The x87 control, status and tag words and last instruction/operand pointers. QEMU
+/// doesn't keep track of the segments of the pointers or what a valid tag holds.
+#[cfg(any(feature = "x86_64", feature = "i386"))]
+pub fn x86_fpu(env: &CPUX86State) -> X87FpuInternalRegs {
+    // Full tag word: 0b11 for empty registers and 0b00 (valid) otherwise
+    let ftag = env.fptags.iter()
+        .enumerate()
+        .fold(0, |ftag, (i, &empty)| ftag | if empty != 0 { 3 << (2 * i) } else { 0 });
+
+    X87FpuInternalRegs {
+        fctrl: env.fpuc as u32,
+        fstat: (env.fpus as u32 & !0x3800) | (env.fpstt & 7) << 11,
+        ftag,
+        fiseg: 0,
+        fioff: env.fpip as u32,
+        foseg: 0,
+        fooff: env.fpdp as u32,
+        fop: env.fpop as u32,
+    }
+}
+
+/// Load any x87 control registers which changed
+#[cfg(any(feature = "x86_64", feature = "i386"))]
+pub fn x86_load_fpu(env: &mut CPUX86State, fpu: &X87FpuInternalRegs, old: &X87FpuInternalRegs) {
+    if fpu.fctrl != old.fctrl {
+        unsafe {
+            panda::sys::cpu_set_fpuc(env, fpu.fctrl as u16);
+        }
+    }
+
+    if fpu.fstat != old.fstat {
+        env.fpstt = (fpu.fstat >> 11) & 7;
+        env.fpus = (fpu.fstat & !0x3800) as u16;
+    }
+
+    if fpu.ftag != old.ftag {
+        for (i, empty) in env.fptags.iter_mut().enumerate() {
+            *empty = ((fpu.ftag >> (2 * i)) & 3 == 3) as u8;
+        }
+    }
+
+    if fpu.fioff != old.fioff {
+        env.fpip = fpu.fioff as u64;
+    }
+
+    if fpu.fooff != old.fooff {
+        env.fpdp = fpu.fooff as u64;
+    }
+
+    if fpu.fop != old.fop {
+        env.fpop = fpu.fop as u16;
+    }
+}
+
+#[cfg(any(feature = "x86_64", feature = "i386"))]
+pub fn x86_xmm(env: &CPUX86State, count: usize) -> Vec<u128> {
+    env.xmm_regs[..count].iter()
+        .map(|reg| unsafe { (reg._q_ZMMReg[1] as u128) << 64 | reg._q_ZMMReg[0] as u128 })
+        .collect()
+}
+
+#[cfg(any(feature = "x86_64", feature = "i386"))]
+pub fn x86_load_xmm(env: &mut CPUX86State, xmm: &[u128]) {
+    for (reg, &val) in env.xmm_regs.iter_mut().zip(xmm) {
+        unsafe {
+            reg._q_ZMMReg[0] = val as u64;
+            reg._q_ZMMReg[1] = (val >> 64) as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RegValue;
+
+    /// Write `bytes` to the register and read them back
+    fn round_trip(mut reg: RegValue<'_>, bytes: &[u8]) -> Vec<u8> {
+        reg.write(bytes).unwrap();
+
+        let mut buf = [0; 64];
+        let len = reg.read(&mut buf);
+        buf[..len].to_vec()
+    }
+
+    #[test]
+    fn round_trips() {
+        let bytes: Vec<u8> = (1..=32).collect();
+
+        let (mut u32_reg, mut u64_reg, mut u128_reg) = (0u32, 0u64, 0u128);
+        assert_eq!(round_trip(RegValue::U32(&mut u32_reg), &bytes[..4]), &bytes[..4]);
+        assert_eq!(round_trip(RegValue::U64(&mut u64_reg), &bytes[..8]), &bytes[..8]);
+        assert_eq!(round_trip(RegValue::U128(&mut u128_reg), &bytes[..16]), &bytes[..16]);
+
+        let mut st = [0u8; 10];
+        assert_eq!(round_trip(RegValue::Bytes(&mut st), &bytes[..10]), &bytes[..10]);
+
+        let mut truncated = 0u64;
+        assert_eq!(round_trip(RegValue::Truncated(&mut truncated, 2), &bytes[..2]), &bytes[..2]);
+
+        let mut vector = [0u128; 2];
+        assert_eq!(round_trip(RegValue::Vector(&mut vector), &bytes), bytes);
+    }
+
+    #[test]
+    fn target_byte_order() {
+        let mut reg = 0u32;
+        RegValue::U32(&mut reg).write(&[0x12, 0x34, 0x56, 0x78]).unwrap();
+
+        if cfg!(any(feature = "ppc", feature = "mips", feature = "mips64")) {
+            assert_eq!(reg, 0x1234_5678);
+        } else {
+            assert_eq!(reg, 0x7856_3412);
+        }
+
+        // Only the low bytes of a truncated register are seen by gdb
+        let mut truncated = 0u64;
+        RegValue::Truncated(&mut truncated, 4).write(&[0x12, 0x34, 0x56, 0x78]).unwrap();
+        if cfg!(any(feature = "ppc", feature = "mips", feature = "mips64")) {
+            assert_eq!(truncated, 0x1234_5678_0000_0000);
+        } else {
+            assert_eq!(truncated, 0x7856_3412);
+        }
+    }
+
+    #[test]
+    fn wrong_size_is_rejected() {
+        let (mut u32_reg, mut u64_reg) = (0u32, 0u64);
+        let mut st = [0u8; 10];
+        let mut vector = [0u128; 2];
+
+        assert!(RegValue::U32(&mut u32_reg).write(&[0; 8]).is_none());
+        assert!(RegValue::U64(&mut u64_reg).write(&[0; 4]).is_none());
+        assert!(RegValue::Bytes(&mut st).write(&[0; 8]).is_none());
+        assert!(RegValue::Truncated(&mut u64_reg, 4).write(&[0; 8]).is_none());
+        assert!(RegValue::Vector(&mut vector).write(&[0; 16]).is_none());
+    }
+}
@@ -0,0 +1,114 @@
+//! Translation between the addresses of the debugged executable as shown by a disassembler,
+//! which loads it at the `base` argument (or Ghidra's default with `ghidra_elf`), and where
+//! it is loaded at runtime, so that addresses can be copied from the disassembler into gdb.
+//!
+//! Only addresses within the executable are translated, anything else (the stack, shared
+//! libraries, the kernel) is left as is. Of the registers only the PC is translated, the link
+//! register and return addresses on the stack keep their runtime values. `absolute_addrs`
+//! turns translation off.
+
+use panda::prelude::*;
+use panda::plugins::osi::OSI;
+
+use crate::args::ARGS;
+use crate::libraries::{exec_mapping, mapping_file};
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+lazy_static::lazy_static! {
+    /// The runtime image of the process the CPU stopped in, looked up once per stop
+    static ref RUNTIME_IMAGE: Mutex<Option<Option<(target_ptr_t, target_ptr_t)>>> = Mutex::new(None);
+
+    pub(crate) static ref BREAKPOINTS: Inserted = Inserted::default();
+    pub(crate) static ref WATCHPOINTS: Inserted = Inserted::default();
+}
+
+/// Where the breakpoints or watchpoints gdb inserted were placed at runtime, by the address
+/// gdb gave. They're removed from there even if another process is current by then, which
+/// would translate the address differently.
+#[derive(Default)]
+pub(crate) struct Inserted(Mutex<HashMap<target_ptr_t, Vec<target_ptr_t>>>);
+
+impl Inserted {
+    /// Record that the address `addr` from gdb was inserted at `runtime_addr`
+    pub fn insert(&self, addr: target_ptr_t, runtime_addr: target_ptr_t) {
+        self.0.lock().unwrap().entry(addr).or_default().push(runtime_addr);
+    }
+
+    /// Where the address `addr` from gdb was inserted, translating it if it wasn't
+    pub fn remove(&self, cpu: &mut CPUState, addr: target_ptr_t) -> target_ptr_t {
+        let runtime_addr = {
+            let mut inserted = self.0.lock().unwrap();
+            let runtime_addrs = inserted.get_mut(&addr);
+            let runtime_addr = runtime_addrs.and_then(|runtime_addrs| runtime_addrs.pop());
+
+            if inserted.get(&addr).map(Vec::is_empty).unwrap_or(false) {
+                inserted.remove(&addr);
+            }
+
+            runtime_addr
+        };
+
+        runtime_addr.unwrap_or_else(|| to_runtime(cpu, addr))
+    }
+
+    pub fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
+
+/// Whether addresses from gdb are relative to a base address
+pub(crate) fn enabled() -> bool {
+    !ARGS.absolute_addrs && ARGS.base_addr() != 0
+}
+
+/// Look up the runtime image again, called when the CPU stops
+pub(crate) fn forget_runtime_image() {
+    *RUNTIME_IMAGE.lock().unwrap() = None;
+}
+
+/// The start and end of the current process's executable in memory, from the first to
+/// the last mapping of its file
+fn runtime_image(cpu: &mut CPUState) -> Option<(target_ptr_t, target_ptr_t)> {
+    *RUNTIME_IMAGE.lock().unwrap().get_or_insert_with(|| find_runtime_image(cpu))
+}
+
+fn find_runtime_image(cpu: &mut CPUState) -> Option<(target_ptr_t, target_ptr_t)> {
+    let mut proc = OSI.get_current_process(cpu);
+    let mappings = OSI.get_mappings(cpu, &mut *proc);
+    let exec = exec_mapping(&mappings, &proc)?;
+    let file = mapping_file(exec);
+
+    let end = mappings.iter()
+        .filter(|mapping| mapping_file(mapping) == file)
+        .map(|mapping| mapping.base.wrapping_add(mapping.size))
+        .max()?;
+
+    Some((exec.base, end))
+}
+
+/// Translate an address from gdb to where it is at runtime
+pub(crate) fn to_runtime(cpu: &mut CPUState, addr: target_ptr_t) -> target_ptr_t {
+    if !enabled() {
+        return addr;
+    }
+
+    let base = ARGS.base_addr();
+    match runtime_image(cpu) {
+        Some((start, end)) if addr >= base && addr - base < end - start => start + (addr - base),
+        _ => addr,
+    }
+}
+
+/// Translate a runtime address to the address gdb knows it by
+pub(crate) fn to_static(cpu: &mut CPUState, addr: target_ptr_t) -> target_ptr_t {
+    if !enabled() {
+        return addr;
+    }
+
+    match runtime_image(cpu) {
+        Some((start, end)) if (start..end).contains(&addr) => ARGS.base_addr() + (addr - start),
+        _ => addr,
+    }
+}
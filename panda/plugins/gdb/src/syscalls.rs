@@ -0,0 +1,324 @@
+//! Stopping on system calls made by the debugged process, for gdb's `catch syscall` and
+//! `monitor catch_syscall`, using the `syscalls2` plugin. syscalls2 doesn't support ppc.
+
+use panda::prelude::*;
+
+use crate::breakpoint_condition::read_ptr;
+use crate::proc_start::read_string;
+
+use std::ffi::CStr;
+use std::fmt;
+use std::os::raw::{c_char, c_int};
+use std::sync::Once;
+
+/// `syscall_info_t` from syscalls2
+#[repr(C)]
+pub struct SyscallInfo {
+    no: c_int,
+    name: *const c_char,
+    nargs: c_int,
+    argt: *const c_int,
+    argsz: *const u8,
+    argn: *const *const c_char,
+    argtn: *const *const c_char,
+    noreturn: bool,
+}
+
+/// `syscall_meta_t` from syscalls2
+#[repr(C)]
+pub struct SyscallMeta {
+    /// The highest system call number, syscalls2's tables have an entry for each number up
+    /// to and including it
+    max: u32,
+    max_generic: u32,
+    max_args: u32,
+}
+
+/// `on_all_sys_enter_t` and `on_all_sys_return_t` from syscalls2
+type SyscallCallback = extern "C" fn(&mut CPUState, target_ulong, target_ulong);
+
+panda::plugin_import! {
+    static SYSCALLS2: Syscalls2 = extern "syscalls2" {
+        fn get_syscall_info(callno: u32) -> *const SyscallInfo;
+        fn get_syscall_meta() -> *const SyscallMeta;
+        fn ppp_add_cb_on_all_sys_enter(callback: SyscallCallback);
+        fn ppp_add_cb_on_all_sys_return(callback: SyscallCallback);
+    };
+}
+
+static REGISTER_CALLBACKS: Once = Once::new();
+
+/// Linux passes at most 6 arguments to a system call
+const MAX_ARGS: usize = 6;
+
+/// A system call as given to `monitor catch_syscall`
+#[derive(Clone, Debug)]
+pub enum SyscallRef {
+    Number(u64),
+    Name(String),
+}
+
+/// An argument as given to `monitor catch_syscall`, either `argN` or its name in syscalls2
+#[derive(Clone, Debug)]
+pub enum ArgRef {
+    Index(usize),
+    Name(String),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum ArgOp {
+    Eq,
+    Ne,
+    Contains,
+}
+
+#[derive(Clone, Debug)]
+pub enum ArgValue {
+    Number(u64),
+    /// Compared against the nul-terminated string the argument points to
+    String(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct ArgFilter {
+    pub arg: usize,
+    pub op: ArgOp,
+    pub value: ArgValue,
+}
+
+/// A system call to stop on when all its filters hold, set with `monitor catch_syscall`
+#[derive(Clone, Debug)]
+pub struct SyscallCatchpoint {
+    pub number: target_ulong,
+    pub filters: Vec<ArgFilter>,
+    pub hit_count: u64,
+}
+
+/// Start getting called back on system calls, the first time any are caught. syscalls2 is
+/// loaded then if it isn't already, so sessions which don't catch system calls don't pay
+/// for it. Loaded this way it doesn't have `load-info=true`.
+pub(crate) fn register_callbacks() {
+    REGISTER_CALLBACKS.call_once(|| {
+        SYSCALLS2.ppp_add_cb_on_all_sys_enter(crate::on_sys_enter);
+        SYSCALLS2.ppp_add_cb_on_all_sys_return(crate::on_sys_return);
+
+        // syscalls2 finds system call instructions as they are translated
+        crate::target_state::STATE.invalidate_translations();
+    });
+}
+
+/// Whether syscalls2 was loaded with `load-info=true`, without which it doesn't know the
+/// names of system calls and their arguments
+fn info_loaded() -> bool {
+    !SYSCALLS2.get_syscall_meta().is_null()
+}
+
+/// The information syscalls2 has on the system call `number`
+fn syscall_info(number: target_ulong) -> Option<&'static SyscallInfo> {
+    // SAFETY: syscalls2 returns either null or a pointer into its static tables
+    let meta = unsafe { SYSCALLS2.get_syscall_meta().as_ref()? };
+    if number > meta.max as target_ulong {
+        return None;
+    }
+
+    let info = unsafe { SYSCALLS2.get_syscall_info(number as u32).as_ref()? };
+
+    // Numbers without a system call have an empty entry
+    if info.name.is_null() {
+        None
+    } else {
+        Some(info)
+    }
+}
+
+fn syscall_number(name: &str) -> Option<target_ulong> {
+    // SAFETY: see `syscall_info`
+    let meta = unsafe { SYSCALLS2.get_syscall_meta().as_ref()? };
+
+    (0..=meta.max as target_ulong).find(|&number| {
+        syscall_info(number)
+            .map(|info| unsafe { CStr::from_ptr(info.name) }.to_bytes() == name.as_bytes())
+            .unwrap_or(false)
+    })
+}
+
+/// The name of the system call `number`, if syscalls2 knows it
+pub fn syscall_name(number: target_ulong) -> Option<String> {
+    let info = syscall_info(number)?;
+
+    // SAFETY: checked to be non-null by `syscall_info`
+    Some(unsafe { CStr::from_ptr(info.name) }.to_string_lossy().into_owned())
+}
+
+fn arg_name(number: target_ulong, arg: usize) -> Option<String> {
+    let info = syscall_info(number)?;
+    if info.argn.is_null() || arg >= info.nargs as usize {
+        return None;
+    }
+
+    // SAFETY: argn has nargs entries
+    let name = unsafe { *info.argn.add(arg) };
+    if name.is_null() {
+        return None;
+    }
+
+    Some(unsafe { CStr::from_ptr(name) }.to_string_lossy().into_owned())
+}
+
+impl SyscallCatchpoint {
+    /// Resolve the system call and argument names of a `monitor catch_syscall` command
+    pub fn new(syscall: SyscallRef, filters: Vec<(ArgRef, ArgOp, ArgValue)>) -> Result<Self, String> {
+        const NO_INFO: &str = "syscall names are only known if syscalls2 is loaded with load-info=true";
+
+        let number = match syscall {
+            SyscallRef::Number(number) => number as target_ulong,
+            SyscallRef::Name(name) => match syscall_number(&name) {
+                Some(number) => number,
+                None if !info_loaded() => return Err(NO_INFO.into()),
+                None => return Err(format!("unknown syscall '{}'", name)),
+            },
+        };
+
+        let filters = filters
+            .into_iter()
+            .map(|(arg, op, value)| {
+                let arg = match arg {
+                    ArgRef::Index(arg) => arg,
+                    ArgRef::Name(name) => (0..MAX_ARGS)
+                        .find(|&arg| arg_name(number, arg).as_deref() == Some(&*name))
+                        .ok_or_else(|| if info_loaded() {
+                            format!("syscall has no argument named '{}'", name)
+                        } else {
+                            NO_INFO.into()
+                        })?,
+                };
+
+                if arg >= MAX_ARGS {
+                    return Err(format!("syscalls have at most {} arguments", MAX_ARGS));
+                }
+
+                Ok(ArgFilter { arg, op, value })
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(SyscallCatchpoint { number, filters, hit_count: 0 })
+    }
+
+    /// Whether the system call about to be made stops the CPU. Like breakpoint conditions,
+    /// a filter which can't be evaluated (e.g. reading unmapped memory) stops it.
+    pub fn matches(&self, cpu: &mut CPUState, number: target_ulong) -> bool {
+        number == self.number && self.filters.iter().all(|filter| filter.holds(cpu) != Some(false))
+    }
+}
+
+impl ArgFilter {
+    fn holds(&self, cpu: &mut CPUState) -> Option<bool> {
+        let arg = syscall_arg(cpu, self.arg)?;
+
+        Some(match (&self.value, self.op) {
+            (ArgValue::Number(num), ArgOp::Eq) => arg as u64 == *num,
+            (ArgValue::Number(num), ArgOp::Ne) => arg as u64 != *num,
+            // The grammar only allows strings with `contains`
+            (ArgValue::Number(_), ArgOp::Contains) => false,
+            (ArgValue::String(string), op) => {
+                let arg = read_string(cpu, arg)?;
+
+                match op {
+                    ArgOp::Eq => arg == *string,
+                    ArgOp::Ne => arg != *string,
+                    ArgOp::Contains => arg.contains(&**string),
+                }
+            }
+        })
+    }
+}
+
+/// Argument `i` of the system call about to be made, following the Linux system call
+/// calling convention
+fn syscall_arg(cpu: &mut CPUState, i: usize) -> Option<target_ulong> {
+    #[cfg(feature = "x86_64")] {
+        use panda::sys::{R_EDI, R_ESI, R_EDX};
+
+        let env = unsafe { &*(cpu.env_ptr as *const panda::sys::CPUX86State) };
+        let regs = [R_EDI, R_ESI, R_EDX, 10, 8, 9];
+
+        regs.get(i).map(|&reg| env.regs[reg as usize])
+    }
+
+    #[cfg(feature = "i386")] {
+        use panda::sys::{R_EBX, R_ECX, R_EDX, R_ESI, R_EDI, R_EBP};
+
+        let env = unsafe { &*(cpu.env_ptr as *const panda::sys::CPUX86State) };
+        let regs = [R_EBX, R_ECX, R_EDX, R_ESI, R_EDI, R_EBP];
+
+        regs.get(i).map(|&reg| env.regs[reg as usize])
+    }
+
+    #[cfg(feature = "arm")] {
+        let env = unsafe { &*(cpu.env_ptr as *const panda::sys::CPUARMState) };
+
+        env.regs[..MAX_ARGS].get(i).copied()
+    }
+
+    #[cfg(feature = "aarch64")] {
+        let env = unsafe { &*(cpu.env_ptr as *const panda::sys::CPUARMState) };
+
+        env.xregs[..MAX_ARGS].get(i).copied()
+    }
+
+    #[cfg(feature = "ppc")] {
+        let env = unsafe { &*(cpu.env_ptr as *const panda::sys::CPUPPCState) };
+
+        env.gpr[3..3 + MAX_ARGS].get(i).copied()
+    }
+
+    // o32 passes the first 4 arguments in a0-a3 and the rest on the stack
+    #[cfg(any(feature = "mips", feature = "mipsel"))] {
+        let env = unsafe { &*(cpu.env_ptr as *const panda::sys::CPUMIPSState) };
+        let gpr = env.active_tc.gpr;
+
+        match i {
+            0..=3 => Some(gpr[4 + i]),
+            _ if i < MAX_ARGS => read_ptr(cpu, gpr[29].wrapping_add(16 + 4 * (i as target_ptr_t - 4))),
+            _ => None,
+        }
+    }
+
+    // n64 passes them in a0-a7
+    #[cfg(feature = "mips64")] {
+        let env = unsafe { &*(cpu.env_ptr as *const panda::sys::CPUMIPSState) };
+
+        env.active_tc.gpr[4..4 + MAX_ARGS].get(i).copied()
+    }
+}
+
+impl fmt::Display for SyscallCatchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match syscall_name(self.number) {
+            Some(name) => write!(f, "{} ({})", name, self.number)?,
+            None => write!(f, "{}", self.number)?,
+        }
+
+        for (i, filter) in self.filters.iter().enumerate() {
+            f.write_str(if i == 0 { " if " } else { " && " })?;
+
+            match arg_name(self.number, filter.arg) {
+                Some(name) => f.write_str(&name)?,
+                None => write!(f, "arg{}", filter.arg)?,
+            }
+
+            f.write_str(match filter.op {
+                ArgOp::Eq => " == ",
+                ArgOp::Ne => " != ",
+                ArgOp::Contains => " contains ",
+            })?;
+
+            match &filter.value {
+                ArgValue::Number(num) => write!(f, "{:#x}", num)?,
+                ArgValue::String(string) => write!(f, "{:?}", string)?,
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,16 +1,57 @@
 use panda::prelude::*;
+use panda::sys::target_pid_t;
+use panda::plugins::osi::OSI;
 
-use std::sync::{RwLock, Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}};
-use std::sync::mpsc::{channel, Sender, Receiver};
-use std::collections::HashSet;
+use std::os::raw::c_int;
+use std::sync::{RwLock, Mutex, atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}};
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::time::Duration;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use gdbstub::target::ext::breakpoints::WatchKind;
+use gdbstub::target::ext::catch_syscalls::CatchSyscallPosition;
+
+use crate::breakpoint_condition::{BreakCondition, Expr};
+use crate::libraries;
+use crate::relocation;
+use crate::syscalls::SyscallCatchpoint;
+
+extern "C" {
+    // Not part of panda-sys' bindings
+    fn tb_invalidate_phys_addr(address_space: *mut panda::sys::AddressSpace, addr: u64);
+}
 
 pub struct State {
     single_step: AtomicBool,
-    exit_kernel: AtomicBool,
+    fully_instrumented: AtomicBool,
+    flush_pending: AtomicBool,
+    registers_written: AtomicBool,
+    /// The instruction execution restarts at after a register write, and whether the block
+    /// starting with it has been entered
+    skip_instruction: Mutex<Option<(target_ptr_t, bool)>>,
+    interrupt: AtomicBool,
+    wait_for_entry: AtomicBool,
+    entry_points: Mutex<HashMap<target_ulong, EntryPoint>>,
+    awaited_program: Mutex<Option<AwaitedProgram>>,
     breakpoints: RwLock<HashSet<target_ptr_t>>,
+    changed_breakpoints: Mutex<HashSet<target_ptr_t>>,
+    conditions: RwLock<BTreeMap<target_ptr_t, BreakCondition>>,
+    watchpoints: RwLock<Vec<(target_ptr_t, target_ptr_t, WatchKind)>>,
+    caught_syscalls: RwLock<CaughtSyscalls>,
+    syscall_catchpoints: RwLock<Vec<SyscallCatchpoint>>,
     cpu: Mutex<Option<usize>>,
+    running_cpu: AtomicUsize,
     pc: AtomicUsize,
+    instr_count: AtomicU64,
+    reverse: Mutex<Option<Reverse>>,
+    restore: Mutex<Option<c_int>>,
     pid: AtomicUsize,
+    asid: RwLock<Option<target_ulong>>,
+    checked_asid: Mutex<Option<target_ulong>>,
+    system_wide: AtomicBool,
+    user_stepping: AtomicBool,
+    threads: RwLock<BTreeSet<(target_pid_t, target_pid_t)>>,
+    current_thread: Mutex<(target_pid_t, target_pid_t)>,
     pub brk: Signal<BreakStatus>,
     pub cont: Signal<()>,
 }
@@ -18,20 +59,112 @@ pub struct State {
 #[derive(Copy, Clone, Debug)]
 pub enum BreakStatus {
     Break,
+    Step,
+    Watch {
+        kind: WatchKind,
+        addr: target_ptr_t,
+    },
+    /// A system call gdb is catching
+    Syscall {
+        number: target_ulong,
+        position: CatchSyscallPosition,
+    },
+    /// A system call matching a `monitor catch_syscall` catchpoint, which gdb doesn't
+    /// know about
+    SyscallCatchpoint,
+    /// gdb interrupted the running guest (Ctrl-C)
+    Interrupt,
+    ReplayStart,
     Exit
 }
 
+/// The system calls gdb asked to stop on with `catch syscall`
+#[derive(Clone, Debug)]
+pub enum CaughtSyscalls {
+    None,
+    All,
+    Numbers(HashSet<target_ulong>),
+}
+
+/// The program to break at the entry point of, given by the `file` argument
+#[derive(Clone, Debug)]
+struct AwaitedProgram {
+    /// Either a full path or a file name
+    file: String,
+    /// How many more times the program has to start before breaking
+    remaining: u32,
+}
+
+/// What's known about the entry point of the program running in an address space
+#[derive(Copy, Clone, Debug)]
+enum EntryPoint {
+    Known(target_ptr_t),
+    /// The executable is mapped at this address, but its ELF header can't be read yet
+    HeaderAt(target_ptr_t),
+    /// No executable is mapped
+    Unknown,
+}
+
+/// Progress of a reverse execution request. Going backwards is done by restoring a
+/// checkpoint and executing forwards again, as replays are deterministic.
+#[derive(Copy, Clone, Debug)]
+enum Reverse {
+    /// Executing from `checkpoint` to find the last instruction before `end` which
+    /// would have stopped the CPU
+    Search {
+        step: bool,
+        checkpoint: c_int,
+        start: Option<u64>,
+        end: u64,
+        last_stop: Option<(u64, BreakStatus)>,
+    },
+    /// Executing from the same checkpoint again, to stop on the instruction `target`
+    Seek {
+        target: u64,
+        status: BreakStatus,
+    },
+}
+
+/// What the CPU should do with an instruction while executing in reverse
+pub enum ReverseAction {
+    Run,
+    Break(BreakStatus),
+    Restore,
+}
+
 impl State {
     fn new() -> Self {
         State {
             single_step: AtomicBool::new(false),
-            exit_kernel: AtomicBool::new(false),
+            fully_instrumented: AtomicBool::new(false),
+            flush_pending: AtomicBool::new(false),
+            registers_written: AtomicBool::new(false),
+            skip_instruction: Mutex::new(None),
+            interrupt: AtomicBool::new(false),
+            wait_for_entry: AtomicBool::new(false),
+            entry_points: Mutex::new(HashMap::new()),
+            awaited_program: Mutex::new(None),
             breakpoints: RwLock::new(HashSet::new()),
+            changed_breakpoints: Mutex::new(HashSet::new()),
+            conditions: RwLock::new(BTreeMap::new()),
+            watchpoints: RwLock::new(Vec::new()),
+            caught_syscalls: RwLock::new(CaughtSyscalls::None),
+            syscall_catchpoints: RwLock::new(Vec::new()),
             brk: Signal::new(),
             cont: Signal::new(),
             cpu: Mutex::new(None),
+            running_cpu: AtomicUsize::new(0),
             pc: AtomicUsize::new(0),
+            instr_count: AtomicU64::new(0),
+            reverse: Mutex::new(None),
+            restore: Mutex::new(None),
             pid: AtomicUsize::new(0),
+            asid: RwLock::new(None),
+            checked_asid: Mutex::new(None),
+            system_wide: AtomicBool::new(false),
+            user_stepping: AtomicBool::new(false),
+            threads: RwLock::new(BTreeSet::new()),
+            current_thread: Mutex::new((0, 0)),
         }
     }
 
@@ -42,30 +175,115 @@ impl State {
             .contains(&pc)
     }
 
-    pub fn exiting_kernel(&self) -> bool {
-        self.exit_kernel.load(Ordering::SeqCst)
+    /// Whether the CPU should break at the entry point of the next program to start, for
+    /// `on_entry`
+    pub fn waiting_for_entry(&self) -> bool {
+        self.wait_for_entry.load(Ordering::SeqCst)
+    }
+
+    pub fn set_wait_for_entry(&self) {
+        self.wait_for_entry.store(true, Ordering::SeqCst)
     }
 
-    #[cfg(feature = "x86_64")]
-    pub fn exited_kernel(&self, pc: target_ptr_t) -> bool {
-        //const MASK: target_ptr_t = 0xffffff000000;
-        //const VALUE: target_ptr_t = 0x555555000000;
+    /// Only break at the entry point of the `instance`th program started from `file`,
+    /// which is either the full path of the executable or its file name
+    pub fn set_wait_for_program(&self, file: &str, instance: u32) {
+        *self.awaited_program.lock().unwrap() = Some(AwaitedProgram {
+            file: file.to_owned(),
+            remaining: instance.max(1),
+        });
+        self.set_wait_for_entry();
+    }
 
-        self.exiting_kernel() && (0x555555554000..0x55555555c000).contains(&pc)
-        //self.exiting_kernel() && (pc & MASK == VALUE)
+    pub fn unset_wait_for_entry(&self) {
+        self.wait_for_entry.store(false, Ordering::SeqCst);
+        self.entry_points.lock().unwrap().clear();
     }
 
-    #[cfg(not(feature = "x86_64"))]
-    pub fn exited_kernel(&self, pc: target_ptr_t) -> bool {
-        self.exiting_kernel()
+    /// Record the entry point of the program running in the address space `asid`, as
+    /// reported by `proc_start_linux` when it starts
+    pub fn set_entry_point(&self, asid: target_ulong, entry: target_ptr_t) {
+        if self.waiting_for_entry() {
+            self.entry_points.lock().unwrap().insert(asid, EntryPoint::Known(entry));
+        }
+    }
+
+    /// Whether the CPU is about to execute the entry point of the program it is running,
+    /// while waiting to break there. The entry point comes from `proc_start_linux` if it
+    /// saw the program start, otherwise from the ELF header of the executable.
+    pub fn at_entry_point(&self, cpu: &mut CPUState, pc: target_ptr_t) -> bool {
+        if !self.waiting_for_entry() || panda::in_kernel_mode(cpu) {
+            return false;
+        }
+
+        // A program gets a new address space when it is exec'd, so its executable is
+        // looked up once per address space. The ELF header may not be paged in yet, in
+        // which case only reading it is tried again on the next instruction.
+        let asid = panda::current_asid(cpu);
+        let known = self.entry_points.lock().unwrap().get(&asid).copied();
+        let base = match known {
+            Some(EntryPoint::Known(entry)) => return self.is_entry_point(cpu, pc, entry),
+            Some(EntryPoint::HeaderAt(base)) => base,
+            Some(EntryPoint::Unknown) => return false,
+            None => match libraries::exec_base(cpu) {
+                Some(base) => base,
+                None => {
+                    self.entry_points.lock().unwrap().insert(asid, EntryPoint::Unknown);
+                    return false;
+                }
+            },
+        };
+
+        let entry = libraries::exec_entry(cpu, base);
+        let known = entry.map(EntryPoint::Known).unwrap_or(EntryPoint::HeaderAt(base));
+        self.entry_points.lock().unwrap().insert(asid, known);
+
+        match entry {
+            Some(entry) => self.is_entry_point(cpu, pc, entry),
+            None => false,
+        }
     }
 
-    pub fn set_exit_kernel(&self) {
-        self.exit_kernel.store(true, Ordering::SeqCst)
+    fn is_entry_point(&self, cpu: &mut CPUState, pc: target_ptr_t, entry: target_ptr_t) -> bool {
+        // The low bit marks Thumb/MIPS16 entry points
+        #[cfg(any(feature = "arm", feature = "mips", feature = "mipsel", feature = "mips64"))]
+        let entry = entry & !1;
+
+        pc == entry && self.is_awaited_program(cpu)
     }
 
-    pub fn unset_exit_kernel(&self) {
-        self.exit_kernel.store(false, Ordering::SeqCst)
+    /// Whether the program which is starting is the one being waited for, counting it as
+    /// one of the instances waited for if it is
+    fn is_awaited_program(&self, cpu: &mut CPUState) -> bool {
+        let mut awaited = self.awaited_program.lock().unwrap();
+        let program = match &mut *awaited {
+            Some(program) => program,
+            None => return true,
+        };
+
+        let path = match libraries::exec_file(cpu, None) {
+            Some(path) => path,
+            None => return false,
+        };
+
+        let matches = if program.file.contains('/') {
+            path == program.file
+        } else {
+            path.rsplit('/').next() == Some(&*program.file)
+        };
+
+        if !matches {
+            return false;
+        }
+
+        program.remaining -= 1;
+        if program.remaining > 0 {
+            println!("[gdb] {} started, waiting for {} more", path, program.remaining);
+            return false;
+        }
+
+        *awaited = None;
+        true
     }
 
     pub fn single_stepping(&self) -> bool {
@@ -75,7 +293,21 @@ impl State {
 
     pub fn start_single_stepping(&self) {
         self.single_step
-            .store(true, Ordering::SeqCst)
+            .store(true, Ordering::SeqCst);
+
+        // Blocks translated while not stepping only have their breakpoints instrumented,
+        // so they need to be retranslated in order to stop on every instruction
+        if !self.fully_instrumented.swap(true, Ordering::SeqCst) {
+            self.invalidate_translations();
+        }
+    }
+
+    /// Retranslate any code translated while single stepping so that instructions
+    /// without breakpoints run uninstrumented again. Used when continuing.
+    pub fn stop_instrumenting_all(&self) {
+        if self.fully_instrumented.swap(false, Ordering::SeqCst) {
+            self.invalidate_translations();
+        }
     }
 
     pub fn stop_single_stepping(&self) {
@@ -109,6 +341,48 @@ impl State {
     pub fn unset_cpu(&self) {
         *self.cpu.lock().unwrap() = None;
     }
+
+    /// Record the CPU executing guest code, so that it can be kicked out of its chain of
+    /// translation blocks when gdb interrupts it
+    pub fn set_running_cpu(&self, cpu: &mut CPUState) {
+        self.running_cpu.store(cpu as *mut _ as usize, Ordering::Relaxed);
+    }
+
+    /// Stop the CPU on the next instruction it executes, whatever is running. Called from
+    /// the debugger thread while the guest runs.
+    pub fn request_interrupt(&self) {
+        self.interrupt.store(true, Ordering::SeqCst);
+
+        // The next instruction is likely in a block translated without instrumentation
+        if !self.fully_instrumented.swap(true, Ordering::SeqCst) {
+            self.invalidate_translations();
+        }
+
+        // The flush only takes effect once the CPU looks up a block, which it may never
+        // do while running chained blocks (e.g. a busy loop)
+        let cpu = self.running_cpu.load(Ordering::Relaxed);
+        if cpu != 0 {
+            unsafe {
+                panda::sys::cpu_exit(cpu as *mut CPUState);
+            }
+        }
+    }
+
+    /// Whether gdb is waiting for the CPU to stop, in which case every instruction has
+    /// to be instrumented
+    pub fn interrupt_requested(&self) -> bool {
+        self.interrupt.load(Ordering::SeqCst)
+    }
+
+    /// Returns true (once) if gdb has interrupted the CPU since the last call
+    pub fn take_interrupt(&self) -> bool {
+        self.interrupt.swap(false, Ordering::SeqCst)
+    }
+
+    /// Drop an interrupt the CPU didn't get to, as it stopped for another reason first
+    pub fn cancel_interrupt(&self) {
+        self.interrupt.store(false, Ordering::SeqCst);
+    }
     
     pub fn set_pc(&self, pc: target_ptr_t) {
         self.pc.store(pc as usize, Ordering::SeqCst);
@@ -118,18 +392,479 @@ impl State {
         self.pc.load(Ordering::SeqCst) as target_ptr_t
     }
 
+    /// Set the number of guest instructions executed when the CPU last broke
+    pub fn set_instr_count(&self, count: u64) {
+        self.instr_count.store(count, Ordering::SeqCst);
+    }
+
+    pub fn get_instr_count(&self) -> u64 {
+        self.instr_count.load(Ordering::SeqCst)
+    }
+
+    /// Begin executing backwards from the current instruction, either a single step
+    /// or until the previous breakpoint. Returns false if there are no checkpoints
+    /// to restore, in which case reverse execution isn't possible.
+    pub fn start_reverse(&self, step: bool) -> bool {
+        let end = self.get_instr_count();
+
+        if unsafe { panda::sys::get_num_checkpoints() } == 0 {
+            return false;
+        }
+
+        let checkpoint = unsafe { panda::sys::get_closest_checkpoint_num(end) };
+        if checkpoint < 1 {
+            return false;
+        }
+
+        *self.reverse.lock().unwrap() = Some(Reverse::Search {
+            step,
+            checkpoint,
+            start: None,
+            end,
+            last_stop: None,
+        });
+
+        // Every instruction needs to be instrumented to find where to stop
+        self.fully_instrumented.store(true, Ordering::SeqCst);
+        self.request_restore(checkpoint);
+
+        true
+    }
+
+    pub fn reversing(&self) -> bool {
+        self.reverse.lock().unwrap().is_some()
+    }
+
+    /// Give up on the current reverse execution, stopping wherever the replay is
+    pub fn cancel_reverse(&self) {
+        *self.reverse.lock().unwrap() = None;
+    }
+
+    /// Whether the current reverse execution is a step, meaning every instruction of
+    /// the debugged process is a potential place to stop
+    pub fn reverse_stepping(&self) -> bool {
+        matches!(*self.reverse.lock().unwrap(), Some(Reverse::Search { step: true, .. }))
+    }
+
+    /// Record that the instruction `count` would have stopped the CPU with `status`
+    /// while searching backwards
+    pub fn reverse_hit(&self, count: u64, status: BreakStatus) {
+        if let Some(Reverse::Search { end, last_stop, .. }) = &mut *self.reverse.lock().unwrap() {
+            if count < *end {
+                *last_stop = Some((count, status));
+            }
+        }
+    }
+
+    /// Advance reverse execution to the instruction `count`
+    pub fn reverse_instruction(&self, count: u64) -> ReverseAction {
+        let mut reverse = self.reverse.lock().unwrap();
+
+        match &mut *reverse {
+            Some(Reverse::Search { step, checkpoint, start, end, last_stop }) => {
+                // The first instruction after restoring the checkpoint
+                let start = *start.get_or_insert(count.saturating_sub(1));
+
+                if count < *end {
+                    return ReverseAction::Run;
+                }
+
+                // Reached the instruction the search started from
+                let (step, checkpoint, last_stop) = (*step, *checkpoint, *last_stop);
+                match last_stop {
+                    Some((target, status)) => {
+                        *reverse = Some(Reverse::Seek { target, status });
+                        self.request_restore(checkpoint);
+                    }
+                    // Nothing to stop on since the checkpoint, search the one before it
+                    None if checkpoint > 1 => {
+                        *reverse = Some(Reverse::Search {
+                            step,
+                            checkpoint: checkpoint - 1,
+                            start: None,
+                            end: start + 1,
+                            last_stop: None,
+                        });
+                        self.request_restore(checkpoint - 1);
+                    }
+                    // Nothing to stop on at all, stop at the start of the replay
+                    None => {
+                        *reverse = Some(Reverse::Seek {
+                            target: start + 1,
+                            status: BreakStatus::ReplayStart,
+                        });
+                        self.request_restore(checkpoint);
+                    }
+                }
+
+                ReverseAction::Restore
+            }
+            Some(Reverse::Seek { target, status }) => {
+                if count < *target {
+                    return ReverseAction::Run;
+                }
+
+                let status = *status;
+                *reverse = None;
+
+                ReverseAction::Break(status)
+            }
+            None => ReverseAction::Run,
+        }
+    }
+
+    fn request_restore(&self, checkpoint: c_int) {
+        *self.restore.lock().unwrap() = Some(checkpoint);
+    }
+
+    /// Whether a checkpoint is waiting to be restored, until which nothing should run
+    pub fn restore_pending(&self) -> bool {
+        self.restore.lock().unwrap().is_some()
+    }
+
+    /// Take the checkpoint to restore, if any
+    pub fn take_restore(&self) -> Option<c_int> {
+        self.restore.lock().unwrap().take()
+    }
+
     pub fn add_breakpoint(&self, pc: target_ptr_t) -> bool {
-        self.breakpoints
+        let inserted = self.breakpoints
             .write()
             .unwrap()
-            .insert(pc)
+            .insert(pc);
+
+        // The instruction may already be translated without instrumentation
+        if inserted {
+            self.breakpoint_changed(pc);
+        }
+
+        inserted
     }
 
     pub fn remove_breakpoint(&self, pc: target_ptr_t) -> bool {
-        self.breakpoints
+        let removed = self.breakpoints
             .write()
             .unwrap()
-            .remove(&pc)
+            .remove(&pc);
+
+        // Drop the instrumentation so the instruction runs at full speed again
+        if removed {
+            self.breakpoint_changed(pc);
+        }
+
+        removed
+    }
+
+    /// Record that the code at `pc` needs to be retranslated. gdb removes every breakpoint
+    /// when the CPU stops and inserts them again when it resumes, so a breakpoint removed
+    /// and added back cancels out.
+    fn breakpoint_changed(&self, pc: target_ptr_t) {
+        let mut changed = self.changed_breakpoints.lock().unwrap();
+
+        if !changed.remove(&pc) {
+            changed.insert(pc);
+        }
+    }
+
+    /// Invalidate the translations of the pages containing breakpoints added or removed
+    /// since the last call. Has to run on the CPU thread, as it modifies the translation
+    /// cache directly. Returns true if anything was invalidated.
+    pub fn invalidate_changed_breakpoints(&self, cpu: &mut CPUState) -> bool {
+        let changed: Vec<_> = self.changed_breakpoints.lock().unwrap().drain().collect();
+        if changed.is_empty() {
+            return false;
+        }
+
+        // Breakpoint addresses are in the debugged process, so they can only be
+        // translated while it's running
+        if !self.in_debugged_process(cpu) {
+            self.invalidate_translations();
+            return true;
+        }
+
+        for pc in changed {
+            let phys = unsafe { panda::sys::panda_virt_to_phys_external(cpu, pc as _) };
+
+            // Not mapped at the moment, so wherever the code is translated is unknown
+            if phys == target_ulong::MAX {
+                self.invalidate_translations();
+                break;
+            }
+
+            unsafe {
+                tb_invalidate_phys_addr(cpu.as_, phys as u64);
+            }
+        }
+
+        true
+    }
+
+    /// Request that all translation blocks be flushed, so that `translate_instr` gets
+    /// a chance to re-evaluate which instructions to instrument.
+    ///
+    /// The flush itself happens the next time the CPU loop looks up a block, see
+    /// `take_flush_pending` for kicking the CPU out of chained blocks.
+    pub fn invalidate_translations(&self) {
+        unsafe {
+            panda::sys::panda_do_flush_tb();
+        }
+        self.flush_pending.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns true (once) if a flush has been requested since the last call
+    pub fn take_flush_pending(&self) -> bool {
+        self.flush_pending.swap(false, Ordering::SeqCst)
+    }
+
+    /// Record that gdb changed the registers of the stopped CPU. The translated code
+    /// for the rest of the block doesn't know about the change, so the block has to be
+    /// exited before resuming.
+    pub fn set_registers_written(&self) {
+        self.registers_written.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns true (once) if registers have been written since the last call
+    pub fn take_registers_written(&self) -> bool {
+        self.registers_written.swap(false, Ordering::SeqCst)
+    }
+
+    /// Don't break on the instruction at `pc` when execution restarts there, as the CPU
+    /// already stopped on it
+    pub fn skip_instruction_at(&self, pc: target_ptr_t) {
+        *self.skip_instruction.lock().unwrap() = Some((pc, false));
+    }
+
+    /// Called before each block. Execution restarts with a block starting at the skipped
+    /// instruction, and if that block isn't instrumented the skip mustn't outlive it.
+    pub fn entering_block(&self, pc: target_ptr_t) {
+        let mut skip = self.skip_instruction.lock().unwrap();
+
+        *skip = match *skip {
+            Some((skip_pc, false)) if skip_pc == pc => Some((skip_pc, true)),
+            _ => None,
+        };
+    }
+
+    /// Returns true (once) if the instruction at `pc` is the one execution restarted at
+    pub fn take_skip_instruction(&self, pc: target_ptr_t) -> bool {
+        matches!(self.skip_instruction.lock().unwrap().take(), Some((skip_pc, _)) if skip_pc == pc)
+    }
+
+    /// Decide whether the breakpoint at `pc` stops the CPU, based on its condition and
+    /// ignore count. Breakpoints without either always stop.
+    ///
+    /// gdb only counts hits which stop, so the hit count is kept here for the rest.
+    pub fn breakpoint_should_stop(&self, cpu: &mut CPUState, pc: target_ptr_t) -> bool {
+        let mut conditions = self.conditions.write().unwrap();
+        let condition = conditions.entry(pc).or_default();
+
+        // Stop if the condition can't be evaluated, so the user can look into it
+        if let Some(expr) = &condition.expr {
+            if expr.eval(cpu) == Some(0) {
+                return false;
+            }
+        }
+
+        condition.hit_count += 1;
+
+        if condition.ignore_count > 0 {
+            condition.ignore_count -= 1;
+            return false;
+        }
+
+        true
+    }
+
+    /// Evaluate the condition of the breakpoint at `pc` without counting a hit
+    pub fn breakpoint_condition_holds(&self, cpu: &mut CPUState, pc: target_ptr_t) -> bool {
+        match self.conditions.read().unwrap().get(&pc) {
+            Some(BreakCondition { expr: Some(expr), .. }) => expr.eval(cpu) != Some(0),
+            _ => true,
+        }
+    }
+
+    /// Set or clear the condition of the breakpoint at `addr`
+    pub fn set_breakpoint_condition(&self, addr: target_ptr_t, expr: Option<Expr>) {
+        self.conditions
+            .write()
+            .unwrap()
+            .entry(addr)
+            .or_default()
+            .expr = expr;
+    }
+
+    /// Don't stop on the next `count` hits of the breakpoint at `addr`
+    pub fn set_breakpoint_ignore_count(&self, addr: target_ptr_t, count: u64) {
+        self.conditions
+            .write()
+            .unwrap()
+            .entry(addr)
+            .or_default()
+            .ignore_count = count;
+    }
+
+    /// Forget the conditions and counters of breakpoints which were deleted. gdb removes
+    /// every breakpoint when the CPU stops and inserts those which still exist before
+    /// resuming, so this has to wait until the CPU resumes.
+    pub fn drop_stale_conditions(&self) {
+        let breakpoints = self.breakpoints.read().unwrap();
+
+        self.conditions
+            .write()
+            .unwrap()
+            .retain(|addr, _| breakpoints.contains(addr));
+    }
+
+    /// Get the conditions and counters of every breakpoint address which has any
+    pub fn breakpoint_conditions(&self) -> Vec<(target_ptr_t, BreakCondition)> {
+        self.conditions
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(&addr, condition)| (addr, condition.clone()))
+            .collect()
+    }
+
+    /// Remove all breakpoints, watchpoints and catchpoints
+    pub fn clear_breakpoints(&self) {
+        let mut breakpoints = self.breakpoints.write().unwrap();
+        if !breakpoints.is_empty() {
+            breakpoints.clear();
+            self.changed_breakpoints.lock().unwrap().clear();
+            self.invalidate_translations();
+        }
+
+        self.conditions.write().unwrap().clear();
+        self.syscall_catchpoints.write().unwrap().clear();
+        self.catch_syscalls(CaughtSyscalls::None);
+
+        let mut watchpoints = self.watchpoints.write().unwrap();
+        if !watchpoints.is_empty() {
+            watchpoints.clear();
+            self.disable_memory_callbacks();
+        }
+
+        relocation::BREAKPOINTS.clear();
+        relocation::WATCHPOINTS.clear();
+    }
+
+    /// Memory callbacks are expensive, so they are only enabled while something is watched.
+    /// Both flags are read when code is translated, so existing translations have to go.
+    fn enable_memory_callbacks(&self) {
+        unsafe {
+            panda::sys::panda_enable_precise_pc();
+            panda::sys::panda_enable_memcb();
+        }
+        self.invalidate_translations();
+    }
+
+    fn disable_memory_callbacks(&self) {
+        unsafe {
+            panda::sys::panda_disable_memcb();
+            panda::sys::panda_disable_precise_pc();
+        }
+        self.invalidate_translations();
+    }
+
+    pub fn add_watchpoint(&self, addr: target_ptr_t, len: target_ptr_t, kind: WatchKind) -> bool {
+        let mut watchpoints = self.watchpoints.write().unwrap();
+
+        if watchpoints.contains(&(addr, len, kind)) {
+            return false;
+        }
+
+        if watchpoints.is_empty() {
+            self.enable_memory_callbacks();
+        }
+
+        watchpoints.push((addr, len, kind));
+
+        true
+    }
+
+    pub fn remove_watchpoint(&self, addr: target_ptr_t, len: target_ptr_t, kind: WatchKind) -> bool {
+        let mut watchpoints = self.watchpoints.write().unwrap();
+
+        let len_before = watchpoints.len();
+        watchpoints.retain(|&watchpoint| watchpoint != (addr, len, kind));
+        let removed = watchpoints.len() != len_before;
+
+        if removed && watchpoints.is_empty() {
+            self.disable_memory_callbacks();
+        }
+
+        removed
+    }
+
+    /// Find a watchpoint triggered by an access of `size` bytes at `addr`. `access` should
+    /// be either `WatchKind::Read` or `WatchKind::Write`, access watchpoints match both.
+    pub fn watchpoint_hit(
+        &self,
+        addr: target_ptr_t,
+        size: usize,
+        access: WatchKind,
+    ) -> Option<(target_ptr_t, WatchKind)> {
+        let watchpoints = self.watchpoints.read().unwrap();
+
+        if watchpoints.is_empty() {
+            return None;
+        }
+
+        let end = addr.wrapping_add(size as target_ptr_t);
+
+        watchpoints
+            .iter()
+            .find(|&&(watch_addr, len, kind)| {
+                let watch_end = watch_addr.wrapping_add(len.max(1));
+
+                (kind == access || kind == WatchKind::ReadWrite) && addr < watch_end && watch_addr < end
+            })
+            .map(|&(watch_addr, _, kind)| (watch_addr, kind))
+    }
+
+    /// Set which system calls gdb stops on, replacing the previous set
+    pub fn catch_syscalls(&self, syscalls: CaughtSyscalls) {
+        *self.caught_syscalls.write().unwrap() = syscalls;
+    }
+
+    pub fn catching_syscalls(&self) -> bool {
+        !matches!(*self.caught_syscalls.read().unwrap(), CaughtSyscalls::None)
+            || !self.syscall_catchpoints.read().unwrap().is_empty()
+    }
+
+    /// Whether gdb asked to stop on the system call `number`
+    pub fn gdb_catches_syscall(&self, number: target_ulong) -> bool {
+        match &*self.caught_syscalls.read().unwrap() {
+            CaughtSyscalls::None => false,
+            CaughtSyscalls::All => true,
+            CaughtSyscalls::Numbers(numbers) => numbers.contains(&number),
+        }
+    }
+
+    pub fn add_syscall_catchpoint(&self, catchpoint: SyscallCatchpoint) {
+        self.syscall_catchpoints.write().unwrap().push(catchpoint);
+    }
+
+    pub fn clear_syscall_catchpoints(&self) {
+        self.syscall_catchpoints.write().unwrap().clear();
+    }
+
+    pub fn syscall_catchpoints(&self) -> Vec<SyscallCatchpoint> {
+        self.syscall_catchpoints.read().unwrap().clone()
+    }
+
+    /// Whether any `monitor catch_syscall` catchpoint stops the CPU on the system call
+    /// `number` it is about to make, counting a hit for each that does
+    pub fn syscall_catchpoint_hit(&self, cpu: &mut CPUState, number: target_ulong) -> bool {
+        let mut hit = false;
+        for catchpoint in self.syscall_catchpoints.write().unwrap().iter_mut() {
+            if catchpoint.matches(cpu, number) {
+                catchpoint.hit_count += 1;
+                hit = true;
+            }
+        }
+
+        hit
     }
 
     pub fn is_pid_set(&self) -> bool {
@@ -150,6 +885,134 @@ impl State {
             x => Some(x as _)
         }
     }
+
+    /// Scope breakpoints, stepping and watchpoints to the process with the given pid,
+    /// identified while running by its address space
+    pub fn attach(&self, pid: target_ulong, asid: target_ulong) {
+        *self.asid.write().unwrap() = Some(asid);
+        *self.checked_asid.lock().unwrap() = None;
+        self.set_pid(pid);
+    }
+
+    /// Stop debugging the attached process and return the guest to free running
+    pub fn detach(&self) {
+        self.unset_pid();
+        *self.asid.write().unwrap() = None;
+
+        self.clear_breakpoints();
+        self.stop_single_stepping();
+        self.stop_instrumenting_all();
+
+        // Release the CPU if it is stopped waiting on the debugger
+        if self.cpu.lock().unwrap().is_some() {
+            self.cont.signal(());
+        }
+    }
+
+    /// Whether the CPU is currently running the debugged process. Always true if
+    /// debugging system-wide or no process has been selected.
+    pub fn in_debugged_process(&self, cpu: &mut CPUState) -> bool {
+        if self.is_system_wide() {
+            return true;
+        }
+
+        let debugged_asid = match *self.asid.read().unwrap() {
+            Some(asid) => asid,
+            None => return true,
+        };
+
+        let asid = panda::current_asid(cpu);
+        if asid == debugged_asid {
+            return true;
+        }
+
+        // The process gets a new address space when it execs, so check the pid of any
+        // other address space, but only once each time it is switched to
+        {
+            let mut checked_asid = self.checked_asid.lock().unwrap();
+            if *checked_asid == Some(asid) {
+                return false;
+            }
+            *checked_asid = Some(asid);
+        }
+
+        let process = OSI.get_current_process(cpu);
+        if Some(process.pid as target_ulong) == self.get_pid() && process.asid as target_ulong == asid {
+            *self.asid.write().unwrap() = Some(asid);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Debug every process (and the kernel) rather than just the debugged process
+    pub fn set_system_wide(&self, system_wide: bool) {
+        self.system_wide.store(system_wide, Ordering::SeqCst);
+    }
+
+    pub fn is_system_wide(&self) -> bool {
+        self.system_wide.load(Ordering::SeqCst)
+    }
+
+    /// Only stop on user mode instructions when stepping, skipping over syscalls and
+    /// interrupt handlers
+    pub fn set_user_stepping(&self, user_stepping: bool) {
+        self.user_stepping.store(user_stepping, Ordering::SeqCst);
+    }
+
+    pub fn is_user_stepping(&self) -> bool {
+        self.user_stepping.load(Ordering::SeqCst)
+    }
+
+    /// Whether a step can stop on the instruction the CPU is about to execute
+    pub fn step_stops_here(&self, cpu: &mut CPUState) -> bool {
+        !self.is_user_stepping() || !panda::in_kernel_mode(cpu)
+    }
+
+    /// Record that a thread with the given pid/tid exists
+    pub fn add_thread(&self, pid: target_pid_t, tid: target_pid_t) {
+        self.threads
+            .write()
+            .unwrap()
+            .insert((pid, tid));
+    }
+
+    pub fn remove_thread(&self, pid: target_pid_t, tid: target_pid_t) {
+        self.threads
+            .write()
+            .unwrap()
+            .remove(&(pid, tid));
+    }
+
+    /// Set the (pid, tid) of the thread the CPU was running when it last broke
+    pub fn set_current_thread(&self, pid: target_pid_t, tid: target_pid_t) {
+        self.add_thread(pid, tid);
+        *self.current_thread.lock().unwrap() = (pid, tid);
+    }
+
+    pub fn get_current_thread(&self) -> (target_pid_t, target_pid_t) {
+        *self.current_thread.lock().unwrap()
+    }
+
+    /// The pid of the process being debugged. If no process has been explicitly
+    /// selected this is the process which was running when the CPU last broke.
+    pub fn debugged_pid(&self) -> target_pid_t {
+        match self.get_pid() {
+            Some(pid) => pid as target_pid_t,
+            None => self.get_current_thread().0,
+        }
+    }
+
+    /// Get the tids of all the threads seen so far belonging to the process `pid`
+    pub fn threads_of(&self, pid: target_pid_t) -> Vec<target_pid_t> {
+        self.threads
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(thread_pid, _)| *thread_pid == pid)
+            .map(|&(_, tid)| tid)
+            .collect()
+    }
 }
 
 pub struct Signal<T> {
@@ -172,6 +1035,15 @@ impl<T: Sized> Signal<T> {
             .unwrap()
     }
 
+    /// Wait for a signal for at most `timeout`
+    pub fn wait_for_timeout(&self, timeout: Duration) -> Option<T> {
+        match self.recv.lock().unwrap().recv_timeout(timeout) {
+            Ok(x) => Some(x),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => panic!("signal disconnected"),
+        }
+    }
+
     pub fn signal(&self, x: T) {
         self.send
             .lock()
@@ -184,3 +1056,52 @@ impl<T: Sized> Signal<T> {
 lazy_static::lazy_static!{
     pub static ref STATE: State = State::new();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::State;
+
+    #[test]
+    fn breakpoint_after_register_write_is_hit() {
+        let state = State::new();
+
+        // `set $rax = 1` while stopped at 0x1000, then `continue`. The block execution
+        // restarts in isn't instrumented, so the skip is never taken there.
+        state.skip_instruction_at(0x1000);
+        state.entering_block(0x1000);
+
+        // The breakpoint in the next block stops the CPU
+        state.entering_block(0x2000);
+        assert!(!state.take_skip_instruction(0x2000));
+    }
+
+    #[test]
+    fn restarted_instruction_is_skipped_once() {
+        let state = State::new();
+
+        state.skip_instruction_at(0x1000);
+        state.entering_block(0x1000);
+        assert!(state.take_skip_instruction(0x1000));
+
+        // Looping back to it breaks again
+        state.entering_block(0x1000);
+        assert!(!state.take_skip_instruction(0x1000));
+    }
+
+    #[test]
+    fn skip_ends_with_the_restarted_block() {
+        let state = State::new();
+
+        // An uninstrumented block looping back to the instruction execution restarted at
+        state.skip_instruction_at(0x1000);
+        state.entering_block(0x1000);
+        state.entering_block(0x1000);
+        assert!(!state.take_skip_instruction(0x1000));
+
+        // `jump` to 0x3000, but an interrupt is taken first
+        state.skip_instruction_at(0x3000);
+        state.entering_block(0x8000);
+        state.entering_block(0x3000);
+        assert!(!state.take_skip_instruction(0x3000));
+    }
+}